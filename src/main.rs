@@ -1,12 +1,20 @@
+#![allow(clippy::needless_return)]
+#![recursion_limit = "256"] // Needed by the chain of routes
+
 mod matchmaking;
 
 use matchmaking::endpoints;
 use matchmaking::entity;
 use matchmaking::database;
+use matchmaking::ping;
+use matchmaking::ratelimit;
+use matchmaking::maps;
 
 use clap::Parser;
 use matchmaking::entity::GameState;
 
+use std::convert::TryFrom;
+use std::io::Read;
 use std::net::ToSocketAddrs;
 use std::path::Path;
 
@@ -36,6 +44,34 @@ struct Args{
     // Serve tick rate
     #[clap(short, long, default_value = "0.020")]
     tick_rate : f32,
+
+    // UDP port of the ping echo service
+    #[clap(long, default_value = "8401")]
+    ping_port : u16,
+
+    // Region of this host
+    #[clap(short, long, default_value = "default")]
+    region : String,
+
+    // YAML file listing additional game hosts
+    #[clap(long)]
+    hosts_file : Option<String>,
+
+    // File with words to filter out of the chat, one per line
+    #[clap(long)]
+    chat_filter_file : Option<String>,
+
+    // YAML file with the request rate limits of each route
+    #[clap(long)]
+    rate_limits_file : Option<String>,
+
+    // Amount of versions stored for each map. Versions used by games are kept too
+    #[clap(long, default_value = "5")]
+    map_versions : usize,
+
+    // Key required by the admin endpoints. They're disabled if missing
+    #[clap(long)]
+    admin_key : Option<String>,
 }
 
 
@@ -65,20 +101,110 @@ async fn main() {
 
     let db = database::DB::new();
 
+    let host = entity::GameHost::new("local".to_string(), args.region.clone(), args.game_address.clone(), args.ping_port, args.server_path.clone());
+    db.host_table.insert(host.id, host);
+    if let Some(hosts_file) = &args.hosts_file
+    {
+        let hosts = read_hosts_file(hosts_file).unwrap_or_else(|err| {
+            println!("Hosts file is invalid: {}", err);
+            std::process::exit(-1);
+        });
+
+        for host in hosts
+        {
+            println!("Registered host {} in region {}", host.name, host.region);
+            db.host_table.insert(host.id, host);
+        }
+    }
+
+    let chat_filter = args.chat_filter_file.as_ref().and_then(|path| {
+        read_chat_filter_file(path).unwrap_or_else(|err| {
+            println!("Chat filter file is invalid: {}", err);
+            std::process::exit(-1);
+        })
+    });
+
+    let rate_limits = match &args.rate_limits_file
+    {
+        Some(path) => std::fs::read_to_string(path).map_err(|err| err.to_string()).and_then(|data| ratelimit::RateLimitConfig::from_yaml(&data))
+            .unwrap_or_else(|err| {
+                println!("Rate limits file is invalid: {}", err);
+                std::process::exit(-1);
+            }),
+        None => ratelimit::RateLimitConfig::default(),
+    };
+    let limiter = ratelimit::RateLimiter::new(rate_limits);
+
+    let ping_address = std::net::SocketAddr::new(address.ip(), args.ping_port);
+    tokio::spawn(async move {
+        if let Err(error) = ping::run_echo_service(ping_address).await
+        {
+            println!("Could not launch ping echo service: {}", error);
+        }
+    });
+
+    let map_catalog = maps::MapCatalog::load(&args.maps_folder, args.map_versions);
+    let _watcher = map_catalog.watch().map_err(|err| println!("Could not watch maps folder: {}", err)).ok();
+
     let copy = db.clone();
+    let catalog_copy = map_catalog.clone();
+    let (tick_rate, port) = (args.tick_rate, args.port);
+    let mm_address = args.game_address.clone();
+    let limiter_copy = limiter.clone();
     std::thread::spawn(move ||{
-        update(&copy);
+        update(&copy, &limiter_copy, &catalog_copy, tick_rate, &mm_address, port);
     });
     
-    let routes = endpoints::filters::get_routes(db, args.server_path, args.maps_folder, args.game_address, args.tick_rate, args.port);
+    let routes = endpoints::filters::get_routes(db, map_catalog, args.tick_rate, args.game_address.clone(), args.port, chat_filter, args.admin_key.clone().filter(|x| !x.is_empty()), limiter);
     warp::serve(routes).run((address.ip(), args.port)).await;
 }
 
 
-fn update(db : &database::DB)
+// Each entry needs a name, region, address, ping_port and server_path.
+// The server path may point to a script which launches the server on a remote machine
+fn read_hosts_file(path : &str) -> Result<Vec<entity::GameHost>, String>
 {
-    let SLEEP_DURATION  = std::time::Duration::from_secs(5);
-    let MAX_DURATION = std::time::Duration::from_secs(60 * 3); // 3 MIN
+    let mut file = std::fs::File::open(path).map_err(|err| err.to_string())?;
+    let mut data_str = String::new();
+    file.read_to_string(&mut data_str).map_err(|err| err.to_string())?;
+    let docs = yaml_rust::YamlLoader::load_from_str(&data_str).map_err(|err| err.to_string())?;
+
+    let mut hosts = Vec::new();
+    let entries = docs.first().and_then(|doc| doc.as_vec()).ok_or("Expected a list of hosts")?;
+    for entry in entries
+    {
+        let field = |name : &str| entry[name].as_str().map(|x| x.to_string()).ok_or(format!("Host entry is missing {}", name));
+        let ping_port = entry["ping_port"].as_i64().ok_or("Host entry is missing ping_port")?;
+        let ping_port = u16::try_from(ping_port).map_err(|_| format!("Host entry has an invalid ping_port {}", ping_port))?;
+        let host = entity::GameHost::new(field("name")?, field("region")?, field("address")?, ping_port, field("server_path")?);
+        hosts.push(host);
+    }
+
+    Ok(hosts)
+}
+
+// Builds a case insensitive regex matching any of the words in the file. None if the file has no words
+fn read_chat_filter_file(path : &str) -> Result<Option<regex::Regex>, String>
+{
+    let data_str = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let words : Vec<String> = data_str.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(regex::escape)
+        .collect();
+    if words.is_empty()
+    {
+        return Ok(None);
+    }
+
+    let pattern = format!(r"(?i)\b({})\b", words.join("|"));
+    regex::Regex::new(&pattern).map(Some).map_err(|err| err.to_string())
+}
+
+fn update(db : &database::DB, limiter : &ratelimit::RateLimiter, map_catalog : &maps::MapCatalog, tick_rate : f32, mm_address : &str, port : u16)
+{
+    let sleep_duration  = std::time::Duration::from_secs(1);
+    let max_duration = std::time::Duration::from_secs(60 * 3); // 3 MIN
     loop {
 
         let now = std::time::SystemTime::now();
@@ -87,13 +213,21 @@ fn update(db : &database::DB)
         games.into_iter().for_each(|game| {
             let elapsed = now.duration_since(game.last_update);
             let elapsed = elapsed.unwrap();
-            if elapsed > MAX_DURATION && matches!(game.state, GameState::InLobby)
+            if elapsed > max_duration && game.state == GameState::Lobby
             {
-                db.game_table.remove(&game.id);
                 println!("Removing AFK game with id {}", game.id);
+                endpoints::handlers::close_game(db, &game.id);
+            }
+            else
+            {
+                endpoints::handlers::update_game_lifecycle(db, game.id, map_catalog, tick_rate, mm_address, port);
             }
         });
 
-        std::thread::sleep(SLEEP_DURATION);
+        limiter.remove_idle();
+        endpoints::handlers::remove_stale_uploads(db, map_catalog);
+        endpoints::handlers::remove_stale_channels(db);
+
+        std::thread::sleep(sleep_duration);
     }
 }
\ No newline at end of file
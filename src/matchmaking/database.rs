@@ -7,6 +7,10 @@ pub type GameTable = Table<entity::Game>;
 pub type PlayerTable = Table<entity::Player>;
 pub type PlayerGameTable = Table<entity::PlayerGame>;
 pub type GameSemTable = Table<entity::GameSem>;
+pub type HostTable = Table<entity::GameHost>;
+pub type PlayerPingTable = Table<entity::PlayerPing>;
+pub type ChatChannelTable = Table<entity::ChatChannel>;
+pub type MapUploadTable = Table<entity::MapUpload>;
 
 #[derive(Clone)]
 pub struct DB{
@@ -14,16 +18,29 @@ pub struct DB{
     pub game_table : GameTable,
     pub player_game_table : PlayerGameTable,
     pub game_sem_table :  GameSemTable,
+    pub host_table : HostTable,
+    pub player_ping_table : PlayerPingTable,
+    pub chat_channel_table : ChatChannelTable,
+    pub map_upload_table : MapUploadTable,
 }
 
 impl DB{
     pub fn new() -> Self{
-        DB{
+        let db = DB{
             player_table : PlayerTable::new(),
             game_table : GameTable::new(),
             player_game_table : PlayerGameTable::new(),
             game_sem_table : GameSemTable::new(),
-        }
+            host_table : HostTable::new(),
+            player_ping_table : PlayerPingTable::new(),
+            chat_channel_table : ChatChannelTable::new(),
+            map_upload_table : MapUploadTable::new(),
+        };
+
+        // Stored from the start, so every request waiting for global messages is woken by the same channel
+        let global_channel = entity::ChatChannel::new_global();
+        db.chat_channel_table.insert(global_channel.id, global_channel);
+        db
     }
 }
 
@@ -66,7 +83,7 @@ impl<T: Clone> Table<T>{
         copy.into_values().collect()
     }
 
-    fn lock(&self) -> std::sync::MutexGuard<HashMap<uuid::Uuid, T>>{
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<uuid::Uuid, T>>{
         self.map.lock().expect("Error on locking")
     }
 }
\ No newline at end of file
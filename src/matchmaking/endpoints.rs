@@ -3,31 +3,56 @@
 pub mod handlers
 {
     use std::fs::File;
+    use std::io::Cursor;
     use std::io::Read;
     use std::io::Seek;
     use std::io::Write;
     use std::path::Path;
     use walkdir::{DirEntry};
-    use yaml_rust::YamlEmitter;
-    use yaml_rust::YamlLoader;
-    use yaml_rust::yaml::Hash;
     use std::convert::Infallible;
     use std::time::Duration;
     use std::process::Command;
 
     use crate::matchmaking::entity::GameState;
+    use crate::matchmaking::entity::get_unix_time_ms;
     use crate::matchmaking::entity::PlayerType;
     use crate::matchmaking::payload;
+    use crate::matchmaking::payload::request::MapMetadata;
+    use crate::matchmaking::payload::response::{Error, ErrorCode};
     use crate::matchmaking::entity;
     use crate::matchmaking::database;
+    use crate::matchmaking::maps;
+    use crate::matchmaking::maps::{MapCatalog, is_map_name_valid};
+    use crate::matchmaking::modes;
+    use crate::matchmaking::ratelimit;
+    use crate::matchmaking::ratelimit::RateLimiter;
 
     use rand::Rng;
-    use ringbuffer::RingBufferExt;
-    use ringbuffer::RingBufferWrite;
+    use sha2::{Digest, Sha256};
+    use regex::Regex;
+    use rand::seq::SliceRandom;
     use warp::reply;
+    use warp::Reply;
     use warp::http::StatusCode;
+    use warp::http::{header, HeaderMap, HeaderValue};
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
     use zip::write::FileOptions;
 
+    const MAX_CHAT_MSG_LEN : usize = 256;
+    const MAX_GAME_NAME_LEN : usize = 32;
+    const MAX_SPECTATORS : u8 = 16;
+    const AUTO_START_COUNTDOWN : Duration = Duration::from_secs(10);
+    const POST_GAME_DURATION : Duration = Duration::from_secs(10); // Time to show the results before going back to the lobby
+    const FAILED_DURATION : Duration = Duration::from_secs(5);
+    const MAX_MAP_UPLOAD_SIZE : u64 = 64 * 1024 * 1024;
+    const MAP_UPLOAD_CHUNK_SIZE : u64 = 1024 * 1024;
+    const MAP_UPLOAD_TIMEOUT : Duration = Duration::from_secs(10 * 60); // Uploads without new chunks for this long are removed
+    const MAX_UPLOADS_PER_IP : usize = 2; // Open at the same time
+    const MAX_STAGED_UPLOAD_SIZE : u64 = 512 * 1024 * 1024; // Sum of the sizes of every open upload
+    const DEFAULT_MAPS_PAGE_SIZE : usize = 20;
+    const MAX_MAPS_PAGE_SIZE : usize = 100;
+    const PICTURE_MAX_AGE : Duration = Duration::from_secs(5 * 60); // Pictures can be replaced, so clients check them again after a while
+
     // /login
     pub async fn login(player : payload::request::Login, db : database::DB) 
         -> Result<impl warp::Reply, warp::Rejection>{
@@ -37,11 +62,11 @@ pub mod handlers
         db.player_table.insert(player.id, player);
         println!("Player login {}", player_copy.id);
 
-        let response = payload::response::Login{id : player_copy.id, username : player_copy.name};
+        let response = payload::response::Login{id : player_copy.id, public_id : player_copy.public_id, username : player_copy.name};
         Ok(warp::reply::json(&response))
     }
 
-    pub async fn list_games(_game_filter : serde_json::Value, db : database::DB) 
+    pub async fn list_games(game_filter : payload::request::ListGames, db : database::DB) 
         -> Result<impl warp::Reply, warp::Rejection>{
         
         let games = db.game_table.get_all();
@@ -49,7 +74,23 @@ pub mod handlers
         let mut game_info_list = Vec::new();
         for game in games.into_iter()
         {
-            let game_info = get_game_info(&db, &game.id).unwrap();
+            let mut game_info = match get_game_info(&db, &game.id)
+            {
+                Ok(game_info) => game_info,
+                Err(_) => continue, // Removed in the meantime
+            };
+            if let Some(region) = &game_filter.region
+            {
+                if game_info.region != *region
+                {
+                    continue;
+                }
+            }
+
+            if let Some(player_id) = game_filter.player_id
+            {
+                game_info.ping = get_host_ping(&db, &game.host_id, Some(&player_id));
+            }
             game_info_list.push(game_info);
         }
 
@@ -57,84 +98,207 @@ pub mod handlers
         Ok(warp::reply::json(&response))
     }
 
-    pub async fn create_game(cg_req : payload::request::CreateGame, db : database::DB, maps_folder : String)
+    pub async fn create_game(cg_req : payload::request::CreateGame, db : database::DB, map_catalog : MapCatalog)
     -> Result<impl warp::Reply, warp::Rejection>
     {
-        let yml = read_map_yaml(&cg_req.map, &maps_folder);
-        let version = yml["version"].as_str().unwrap().to_string();
+        if db.player_table.get(&cg_req.player_id).is_none()
+        {
+            let err = Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", cg_req.player_id));
+            return Ok(error_reply(err));
+        }
+
+        if !is_map_name_valid(&cg_req.map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        // Held until the game is stored, so the map can't be removed in the meantime
+        let _guard = map_catalog.lock_use();
+        let map_info = match map_catalog.get(&cg_req.map)
+        {
+            Ok(map_entry) => map_entry.info,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        let mode = match check_game_settings(&map_info, &cg_req.name, &cg_req.mode, cg_req.max_players, cg_req.max_spectators)
+        {
+            Ok(mode) => mode.name.to_string(),
+            Err(err) => return Ok(error_reply(err)),
+        };
+
+        let host = match select_host(&db, &cg_req.player_id, cg_req.region.as_ref())
+        {
+            Some(host) => host,
+            None => {
+                let err = Error::new(ErrorCode::HostNotFound, format!("Could not find a host in region {}", cg_req.region.unwrap_or_default()));
+                return Ok(error_reply(err));
+            }
+        };
             
-        let game = entity::Game::new(cg_req.name, cg_req.map, version, cg_req.mode, cg_req.max_players);
+        let name = cg_req.name.trim().to_string();
+        let mut game = entity::Game::new(name, cg_req.map, map_info.map_version, mode, cg_req.max_players, cg_req.max_spectators, host.id);
+        game.map_zip_sha256 = map_info.zip_sha256;
+        game.map_picture_sha256 = map_info.picture_sha256;
+        game.auto_start = cg_req.auto_start;
         println!("Game key is {}", game.key);
-        db.game_table.insert(game.id.clone(), game.clone());
+        db.game_table.insert(game.id, game.clone());
 
         let game_sem = entity::GameSem::new(game.id);
         db.game_sem_table.insert(game.id, game_sem);
 
-        join_game_as(&db, cg_req.player_id, game.id, true).unwrap();
+        if join_game_as(&db, cg_req.player_id, game.id, PlayerType::Host).is_err()
+        {
+            db.game_table.remove(&game.id);
+            db.game_sem_table.remove(&game.id);
+            let err = Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", cg_req.player_id));
+            return Ok(error_reply(err));
+        }
 
         // Send response
-        let response = get_game_details(&db, &game.id).unwrap();
-        return Ok(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK));
+        return Ok(game_details_reply(&db, &game.id));
     }
 
-    pub async fn edit_game(eg_req : payload::request::EditGame, db : database::DB, maps_folder : String)
+    pub async fn edit_game(eg_req : payload::request::EditGame, db : database::DB, map_catalog : MapCatalog)
     -> Result<impl warp::Reply, warp::Rejection>
     {
         let player_id = eg_req.player_id;
-        if let Some(player_game) = db.player_game_table.get(&player_id)
+        if let Some(player_game) = db.player_game_table.get(&player_id).filter(|x| x.game_id == eg_req.game_id)
         {
             if matches!(player_game.player_type, PlayerType::Host)
             {
-                let yml = read_map_yaml(&eg_req.map, &maps_folder);
-                let version = yml["version"].as_str().unwrap().to_string();
+                if !is_map_name_valid(&eg_req.map)
+                {
+                    let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+                    return Ok(error_reply(err));
+                }
+
+                let _guard = map_catalog.lock_use();
+                let map_info = match map_catalog.get(&eg_req.map)
+                {
+                    Ok(map_entry) => map_entry.info,
+                    Err(err) => return Ok(error_reply(err)),
+                };
                 
                 if let Some(mut game) = db.game_table.get(&eg_req.game_id)
                 {
-                    game.name = eg_req.name; game.map = eg_req.map; game.mode = eg_req.mode; game.map_version = version;
+                    if !game.state.is_lobby()
+                    {
+                        let err = Error::new(ErrorCode::InvalidGameState, "Game was not in lobby");
+                        return Ok(error_reply(err));
+                    }
+
+                    let mode = match check_game_settings(&map_info, &eg_req.name, &eg_req.mode, game.max_players, game.max_spectators)
+                    {
+                        Ok(mode) => mode.name.to_string(),
+                        Err(err) => return Ok(error_reply(err)),
+                    };
+
+                    // Keeping the same map keeps its version. Changing it picks the current version of the new one
+                    let mode_changed = game.mode != mode;
+                    if game.map != eg_req.map
+                    {
+                        game.map_version = map_info.map_version;
+                        game.map_zip_sha256 = map_info.zip_sha256;
+                        game.map_picture_sha256 = map_info.picture_sha256;
+                    }
+                    game.name = eg_req.name.trim().to_string(); game.map = eg_req.map; game.mode = mode;
+                    game.auto_start = eg_req.auto_start.unwrap_or(game.auto_start);
                     //println!("Game key is {}", game.key);
-                    db.game_table.insert(game.id.clone(), game.clone());
+                    db.game_table.insert(game.id, game.clone());
+                    if mode_changed
+                    {
+                        assign_teams(&db, &game, false);
+                    }
                     
                     get_game_players(&db, &eg_req.game_id).into_iter().for_each(|x| set_player_ready(&db, &x.id, false));
+                    update_auto_start(&db, &eg_req.game_id);
                     notify_game_update(&db, &eg_req.game_id);
 
                     // Send response
-                    let response = get_game_details(&db, &game.id).unwrap();
-                    return Ok(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK));
+                    return Ok(game_details_reply(&db, &game.id));
                 }
-                let err = format!("Could not find game with id {}", eg_req.game_id.to_string());
-                return Ok(warp::reply::with_status(reply::json(&err), StatusCode::NOT_FOUND))
+                let err = Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", eg_req.game_id));
+                return Ok(error_reply(err))
             }
-            let err = format!("Player was not host");
-            return Ok(warp::reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+            let err = Error::new(ErrorCode::NotHost, "Player was not host");
+            return Ok(error_reply(err));
         }
-        let err = format!("Could not find player with id {}", eg_req.game_id.to_string());
-        Ok(warp::reply::with_status(reply::json(&err), StatusCode::NOT_FOUND))
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Could not find player {} in game with id {}", eg_req.player_id, eg_req.game_id));
+        Ok(error_reply(err))
     }
 
 
     pub async fn join_game(join_game_req : payload::request::JoinGame, db : database::DB)
         -> Result<impl warp::Reply, Infallible>
     {
-        let res = join_game_as(&db, join_game_req.player_id, join_game_req.game_id, false);
+        let player_type = if join_game_req.spectator {PlayerType::Spectator} else {PlayerType::Player(false)};
+        let res = join_game_as(&db, join_game_req.player_id, join_game_req.game_id, player_type);
         if let Ok(player_game) = res
         {
             // Notify
+            update_auto_start(&db, &player_game.game_id);
             notify_game_update(&db, &player_game.game_id);
 
             // Send response
-            let response = get_game_details(&db, &join_game_req.game_id).unwrap();
-            return Ok(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK));
+            return Ok(game_details_reply(&db, &join_game_req.game_id));
         }
 
-        let mut err = format!("Could not find game with id {}", join_game_req.game_id.to_string());
-        match res {
-            Err(JoinGameError::GameFull) => err = format!("Game was full"),
-            Err(JoinGameError::GameNotFound) => err = format!("Could not find game with id {}", join_game_req.game_id.to_string()),
-            Err(JoinGameError::PlayerNotFound) => err = format!("Could not find player with id {}", join_game_req.player_id.to_string()),
-            _ => {}
-        }
+        let err = match res {
+            Err(JoinGameError::GameFull) => Error::new(ErrorCode::GameFull, "Game was full"),
+            Err(JoinGameError::PlayerNotFound) => Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", join_game_req.player_id)),
+            Err(JoinGameError::PlayerBanned) => Error::new(ErrorCode::PlayerBanned, "Player was banned from this game"),
+            Err(JoinGameError::NotJoinable) => Error::new(ErrorCode::InvalidGameState, "Game cannot be joined right now"),
+            _ => Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", join_game_req.game_id)),
+        };
         
-        Ok(warp::reply::with_status(reply::json(&err), StatusCode::NOT_FOUND))
+        Ok(error_reply(err))
+    }
+
+    pub async fn quick_play(quick_play_req : payload::request::QuickPlay, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = quick_play_req.player_id;
+        if db.player_table.get(&player_id).is_none()
+        {
+            let err = Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", player_id));
+            return Ok(error_reply(err));
+        }
+
+        // Games in the preferred region go first, then the ones with lower ping and then the most populated ones
+        let preferred_region = quick_play_req.region.or_else(|| get_player_region(&db, &player_id));
+        let player_ping = db.player_ping_table.get(&player_id).unwrap_or_default();
+        let mut candidates : Vec<(bool, u16, u8, uuid::Uuid)> = Vec::new();
+        for game in db.game_table.get_all().into_iter()
+        {
+            if !game.state.is_lobby() || is_game_full(&db, &game.id).unwrap_or(true)
+            {
+                continue;
+            }
+
+            let game_info = match get_game_info(&db, &game.id)
+            {
+                Ok(game_info) => game_info,
+                Err(_) => continue, // Removed in the meantime
+            };
+            let other_region = preferred_region.as_ref().map(|region| *region != game_info.region).unwrap_or(false);
+            let ping = player_ping.pings.get(&game.host_id).copied().unwrap_or(u16::MAX);
+            candidates.push((other_region, ping, u8::MAX - game_info.players, game.id));
+        }
+        candidates.sort();
+
+        for (_, _, _, game_id) in candidates
+        {
+            if let Ok(player_game) = join_game_as(&db, player_id, game_id, PlayerType::Player(false))
+            {
+                update_auto_start(&db, &player_game.game_id);
+                notify_game_update(&db, &player_game.game_id);
+
+                return Ok(game_details_reply(&db, &game_id));
+            }
+        }
+
+        let err = Error::new(ErrorCode::NoGameAvailable, "Could not find an available game");
+        Ok(error_reply(err))
     }
 
     // TODO: Call this after a player is AFK for a long time.
@@ -156,35 +320,261 @@ pub mod handlers
         {
             if let entity::PlayerType::Player(ready) = player_game.player_type
             {
+                let game = match db.game_table.get(&player_game.game_id)
+                {
+                    Some(game) => game,
+                    None => return Ok(error_reply(Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", player_game.game_id)))),
+                };
+                if !game.state.is_lobby()
+                {
+                    let err = Error::new(ErrorCode::InvalidGameState, "Game was not in lobby");
+                    return Ok(error_reply(err));
+                }
+
                 player_game.player_type = entity::PlayerType::Player(!ready);
                 let response = serde_json::json!({"ready" : ready});
 
                 db.player_game_table.insert(player_id, player_game.clone());
+                update_auto_start(&db, &player_game.game_id);
                 notify_game_update(&db, &player_game.game_id);
                 
                 return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
             }
             
-            let err = format!("Player was host. Cannot set ready {}", player_id.to_string());
-            return Ok(reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST));
+            let err = Error::new(ErrorCode::InvalidRequest, format!("Only players can set ready {}", player_id));
+            return Ok(error_reply(err));
         }
             
-        let err = format!("Player was not in a game {}", player_id.to_string());
-        return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id));
+        return Ok(error_reply(err));
+    }
+
+    pub async fn kick_player(kick_player_req : payload::request::KickPlayer, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let target_id = match get_player_id(&db, &kick_player_req.target_id)
+        {
+            Ok(target_id) => target_id,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        match remove_player_as_host(&db, kick_player_req.player_id, target_id, false)
+        {
+            Ok(_) => Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK)),
+            Err(err) => Ok(error_reply(err)),
+        }
+    }
+
+    pub async fn ban_player(ban_player_req : payload::request::BanPlayer, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let target_id = match get_player_id(&db, &ban_player_req.target_id)
+        {
+            Ok(target_id) => target_id,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        match remove_player_as_host(&db, ban_player_req.player_id, target_id, true)
+        {
+            Ok(_) => Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK)),
+            Err(err) => Ok(error_reply(err)),
+        }
+    }
+
+    pub async fn mute_player(mute_player_req : payload::request::MutePlayer, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = mute_player_req.player_id;
+        let target_id = match get_player_id(&db, &mute_player_req.target_id)
+        {
+            Ok(target_id) => target_id,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        let mut game = match get_lobby_as_host(&db, &player_id)
+        {
+            Ok(game) => game,
+            Err(err) => return Ok(error_reply(err)),
+        };
+
+        let game_id = game.id;
+        if db.player_game_table.get(&target_id).filter(|entry| entry.game_id == game_id && entry.player_id != player_id).is_none()
+        {
+            let err = Error::new(ErrorCode::PlayerNotInGame, format!("Could not find player {} in game with id {}", mute_player_req.target_id, game_id));
+            return Ok(error_reply(err));
+        }
+
+        game.muted.retain(|id| *id != target_id);
+        if mute_player_req.muted
+        {
+            game.muted.push(target_id);
+        }
+        db.game_table.insert(game_id, game);
+        notify_game_update(&db, &game_id);
+
+        Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK))
+    }
+
+    pub async fn transfer_host(transfer_host_req : payload::request::TransferHost, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = transfer_host_req.player_id;
+        let target_id = match get_player_id(&db, &transfer_host_req.target_id)
+        {
+            Ok(target_id) => target_id,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        if let Some(mut player_game) = db.player_game_table.get(&player_id)
+        {
+            if matches!(player_game.player_type, PlayerType::Host)
+            {
+                let game_id = player_game.game_id;
+                if let Some(target_game) = db.player_game_table.get(&target_id).filter(|entry| entry.game_id == game_id && entry.player_id != player_id)
+                {
+                    if matches!(target_game.player_type, PlayerType::Spectator)
+                    {
+                        let err = Error::new(ErrorCode::InvalidTarget, "Spectators cannot be host");
+                        return Ok(error_reply(err));
+                    }
+
+
+                    player_game.player_type = PlayerType::Player(false);
+                    db.player_game_table.insert(player_id, player_game);
+                    set_game_host(&db, target_game);
+                    update_auto_start(&db, &game_id);
+                    notify_game_update(&db, &game_id);
+
+                    return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
+                }
+
+                let err = Error::new(ErrorCode::PlayerNotInGame, format!("Could not find player {} in game with id {}", transfer_host_req.target_id, game_id));
+                return Ok(error_reply(err));
+            }
+
+            let err = Error::new(ErrorCode::NotHost, "Player was not host");
+            return Ok(error_reply(err));
+        }
+
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id));
+        Ok(error_reply(err))
+    }
+
+    pub async fn select_team(select_team_req : payload::request::SelectTeam, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = select_team_req.player_id;
+        let team = select_team_req.team;
+        if let Some(mut player_game) = db.player_game_table.get(&player_id)
+        {
+            let game = match db.game_table.get(&player_game.game_id)
+            {
+                Some(game) => game,
+                None => return Ok(error_reply(Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", player_game.game_id)))),
+            };
+            if !game.state.is_lobby()
+            {
+                let err = Error::new(ErrorCode::InvalidGameState, "Game was not in lobby");
+                return Ok(error_reply(err));
+            }
+            if game.teams_locked
+            {
+                let err = Error::new(ErrorCode::TeamsLocked, "Teams are locked");
+                return Ok(error_reply(err));
+            }
+            if matches!(player_game.player_type, PlayerType::Spectator)
+            {
+                let err = Error::new(ErrorCode::InvalidRequest, "Spectators cannot join a team");
+                return Ok(error_reply(err));
+            }
+            if team >= modes::get_team_count(&game.mode)
+            {
+                let err = Error::new(ErrorCode::InvalidTeam, format!("Team {} does not exist in mode {}", team, game.mode));
+                return Ok(error_reply(err));
+            }
+            if player_game.team != Some(team)
+            {
+                if get_team_sizes(&db, &game)[team as usize] >= modes::get_team_cap(&game.mode, game.max_players)
+                {
+                    let err = Error::new(ErrorCode::TeamFull, format!("Team {} was full", team));
+                    return Ok(error_reply(err));
+                }
+
+                player_game.team = Some(team);
+                db.player_game_table.insert(player_id, player_game);
+                notify_game_update(&db, &game.id);
+            }
+
+            return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
+        }
+
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id));
+        Ok(error_reply(err))
+    }
+
+    pub async fn lock_teams(lock_teams_req : payload::request::LockTeams, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        match get_lobby_as_host(&db, &lock_teams_req.player_id)
+        {
+            Ok(mut game) => {
+                game.teams_locked = lock_teams_req.locked;
+                db.game_table.insert(game.id, game.clone());
+                notify_game_update(&db, &game.id);
+
+                Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK))
+            },
+            Err(err) => Ok(error_reply(err)),
+        }
+    }
+
+    pub async fn shuffle_teams(shuffle_teams_req : payload::request::ShuffleTeams, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        match get_lobby_as_host(&db, &shuffle_teams_req.player_id)
+        {
+            Ok(game) => {
+                assign_teams(&db, &game, true);
+                notify_game_update(&db, &game.id);
+
+                Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK))
+            },
+            Err(err) => Ok(error_reply(err)),
+        }
+    }
+
+    pub async fn balance_teams(balance_teams_req : payload::request::BalanceTeams, db : database::DB)
+        -> Result<impl warp::Reply, Infallible>
+    {
+        match get_lobby_as_host(&db, &balance_teams_req.player_id)
+        {
+            Ok(game) => {
+                balance_game_teams(&db, &game);
+                notify_game_update(&db, &game.id);
+
+                Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK))
+            },
+            Err(err) => Ok(error_reply(err)),
+        }
     }
 
-    pub async fn send_chat_msg(scm_req : payload::request::SendChatMsg, db : database::DB)
+    pub async fn send_chat_msg(scm_req : payload::request::SendChatMsg, db : database::DB, chat_filter : Option<Regex>, limiter : RateLimiter)
     -> Result<impl warp::Reply, Infallible>
     {
         let player_id = scm_req.player_id;
-        if let Some(player_game)  = db.player_game_table.get(&player_id)
+        let game = db.player_game_table.get(&player_id).and_then(|x| db.game_table.get(&x.game_id));
+        if let (Some(mut game), Some(player)) = (game, db.player_table.get(&player_id))
         {
-            let mut game = db.game_table.get(&player_game.game_id).unwrap();
-            let player = db.player_table.get(&player_game.player_id).unwrap();
-            let game_id = game.id.clone();
+            let game_id = game.id;
+
+            if game.muted.contains(&player_id)
+            {
+                let err = Error::new(ErrorCode::PlayerMuted, "Player was muted by the host");
+                return Ok(error_reply(err));
+            }
 
-            let chat_msg = player.name + ": " + &scm_req.msg + "\n";
-            game.chat.push(chat_msg);
+            let msg = match moderate_chat_msg(&limiter, &player_id, &scm_req.msg, &chat_filter)
+            {
+                Ok(msg) => msg,
+                Err(err) => return Ok(error_reply(err)),
+            };
+            game.chat.push(Some(&player), entity::ChatMsgKind::Player, msg);
             db.game_table.insert(game.id, game);
 
             notify_game_update(&db, &game_id);
@@ -192,21 +582,95 @@ pub mod handlers
             return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
         }
             
-        let err = format!("Player was not in a game {}", player_id.to_string());
-        return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id));
+        return Ok(error_reply(err));
+    }
+
+    pub async fn get_chat(get_chat_req : payload::request::GetChat, db : database::DB)
+    -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = get_chat_req.player_id;
+        if let Some(player_game)  = db.player_game_table.get(&player_id)
+        {
+            if let Some(game) = db.game_table.get(&player_game.game_id)
+            {
+                let response = payload::response::Chat{msgs : game.chat.get_after(get_chat_req.after_id)};
+                return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+            }
+        }
+            
+        let err = Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id));
+        return Ok(error_reply(err));
+    }
+
+    pub async fn send_channel_msg(scm_req : payload::request::SendChannelMsg, db : database::DB, chat_filter : Option<Regex>, limiter : RateLimiter)
+    -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = scm_req.player_id;
+        let player = match db.player_table.get(&player_id)
+        {
+            Some(player) => player,
+            None => return Ok(error_reply(Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", player_id)))),
+        };
+        let mut channel = match get_chat_channel(&db, &player_id, scm_req.target_id)
+        {
+            Ok(channel) => channel,
+            Err(err) => return Ok(error_reply(err)),
+        };
+
+        let msg = match moderate_chat_msg(&limiter, &player_id, &scm_req.msg, &chat_filter)
+        {
+            Ok(msg) => msg,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        channel.chat.push(Some(&player), entity::ChatMsgKind::Player, msg);
+        db.chat_channel_table.insert(channel.id, channel.clone());
+        channel.notify.notify_waiters();
+
+        Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK))
+    }
+
+    pub async fn get_channel_msgs(gcm_req : payload::request::GetChannelMsgs, db : database::DB)
+    -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = gcm_req.player_id;
+        if db.player_table.get(&player_id).is_none()
+        {
+            let err = Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", player_id));
+            return Ok(error_reply(err));
+        }
+        let channel = match get_chat_channel(&db, &player_id, gcm_req.target_id)
+        {
+            Ok(channel) => channel,
+            Err(err) => return Ok(error_reply(err)),
+        };
+
+        // Created before reading the messages, so one sent in the meantime still wakes this request
+        let notified = channel.notify.notified();
+        let get_msgs = || db.chat_channel_table.get(&channel.id).map(|x| x.chat.get_after(gcm_req.after_id)).unwrap_or_default();
+        let mut msgs = get_msgs();
+        if msgs.is_empty() && gcm_req.wait
+        {
+            let _ = tokio::time::timeout(Duration::from_secs(5), notified).await;
+            msgs = get_msgs();
+        }
+
+        let response = payload::response::Chat{msgs};
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
     }
 
     pub async fn update_game(update_game_req : payload::request::UpdateGame, db : database::DB) -> Result<impl warp::Reply, Infallible>
     {
         let game_id = update_game_req.game_id;
 
-        if let Some(game) = db.game_table.get(&game_id)
+        if let (Some(game), Some(game_sem)) = (db.game_table.get(&game_id), db.game_sem_table.get(&game_id))
         {
-            let game_sem = db.game_sem_table.get(&game_id).expect("Inconsistency: Game had no lock");
-            
             if !update_game_req.forced
             {
-                game_sem.sem.wait_timeout(game_sem.mutex.lock().unwrap(), Duration::from_secs(5)).unwrap();
+                if let Ok(lock) = game_sem.mutex.lock()
+                {
+                    let _lock = game_sem.sem.wait_timeout(lock, Duration::from_secs(5));
+                }
             }
 
             if let Ok(response) = get_game_details(&db, &game.id)
@@ -215,102 +679,106 @@ pub mod handlers
             }
         }
 
-        let err = format!("Could not find game with id {}", game_id.to_string());
-        Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND))
+        let err = Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", game_id));
+        Ok(error_reply(err))
     }
 
-    pub async fn start_game(start_game_req : payload::request::StartGame, db : database::DB, exec_path : 
-            String, maps_folder : String, public_address : String, server_tickrate : f32, mm_port : u16) 
+    pub async fn start_game(start_game_req : payload::request::StartGame, db : database::DB, 
+            map_catalog : MapCatalog, server_tickrate : f32, mm_address : String, mm_port : u16) 
         -> Result<impl warp::Reply, Infallible>
     {
         let game_id = start_game_req.game_id;
 
-        if let Some(mut game) = db.game_table.get(&game_id)
+        if let Some(game) = db.game_table.get(&game_id)
         {
-            if let GameState::InLobby = game.state
+            if game.state.is_lobby()
             {
                 let player_id = start_game_req.player_id;
-                if let Some(player_game) = db.player_game_table.get(&player_id)
+                if let Some(player_game) = db.player_game_table.get(&player_id).filter(|x| x.game_id == game_id)
                 {
                     if matches!(player_game.player_type, PlayerType::Host)
                     {
-                        if let Ok((_address, port)) = launch_game(&db, game_id, exec_path, maps_folder, server_tickrate, mm_port)
+                        if start_game_fn(&db, game_id, &map_catalog, server_tickrate, &mm_address, mm_port).is_ok()
                         {
-                            game.port = Some(port);
-    
-                            game.address = Some(public_address);
-                            game.state = GameState::InGame;
-
-                            db.game_table.insert(game.id, game);
-                            get_game_players(&db, &game_id).into_iter().for_each(|x| set_player_ready(&db, &x.id, false));
-                            notify_game_update(&db, &game_id);
-
                             return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
                         }
-                        let err = format!("Could not launch game");
-                        return Ok(reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST));
+                        let err = Error::new(ErrorCode::LaunchFailed, "Could not launch game");
+                        return Ok(error_reply(err));
                     }
-                    let err = format!("Player was not host");
-                    return Ok(reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST));
+                    let err = Error::new(ErrorCode::NotHost, "Player was not host");
+                    return Ok(error_reply(err));
                 }
-                let err = format!("Could not find player {} in game with id {}", player_id.to_string(), game_id.to_string());
-                return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+                let err = Error::new(ErrorCode::PlayerNotInGame, format!("Could not find player {} in game with id {}", player_id, game_id));
+                return Ok(error_reply(err));
             }
-            let err = format!("Game was not in lobby");
-            return Ok(reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST));
+            let err = Error::new(ErrorCode::InvalidGameState, "Game was not in lobby");
+            return Ok(error_reply(err));
         }
-        let err = format!("Could not find game with id {}", game_id.to_string());
-        return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+        let err = Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", game_id));
+        return Ok(error_reply(err));
     }
 
-    pub async fn get_available_maps(maps_folder : String) -> Result<impl warp::Reply, warp::Rejection>
+    pub async fn get_available_maps(maps_req : payload::request::GetAvailableMaps, map_catalog : MapCatalog) -> Result<impl warp::Reply, Infallible>
     {
-        let maps : Vec<String>;
-        let maps_folder_path = Path::new(&maps_folder);
-        let paths = maps_folder_path.read_dir().unwrap();
-        maps = paths.into_iter()
-        .filter(|r| r.is_ok()) 
-        .map(|r| r.unwrap().path()) 
-        .filter(|r| r.is_dir()) 
-        .map(|r| r.file_name().unwrap().to_str().unwrap().to_string())
-        .collect();
-        
-        let mut maps_info = Vec::<payload::response::MapInfo>::new();
-        for map in maps
+        let page_size = maps_req.page_size.unwrap_or(DEFAULT_MAPS_PAGE_SIZE);
+        if page_size == 0 || page_size > MAX_MAPS_PAGE_SIZE
         {
-            let yml = read_map_yaml(&map, &maps_folder);
-            let game_modes = yml["gamemodes"].as_vec().unwrap().into_iter().map(|x| x.as_str().unwrap().to_string()).collect();
-            if let Some(version) = yml["version"].as_str()
-            {
-                let map_info = payload::response::MapInfo{ map_name : map, supported_gamemodes : game_modes, map_version : version.to_string() };
-                maps_info.push(map_info);
-            }            
+            let err = Error::new(ErrorCode::InvalidRequest, format!("Page size must be between 1 and {}", MAX_MAPS_PAGE_SIZE));
+            return Ok(error_reply(err));
         }
 
-        let response = payload::response::AvailableMaps{maps : maps_info};
-        Ok(warp::reply::json(&response))
+        let search = maps_req.search.map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty());
+        let tags : Vec<String> = maps_req.tags.iter().map(|x| x.trim().to_lowercase()).collect();
+        let mut maps_info : Vec<payload::response::MapInfo> = map_catalog.get_all().into_iter()
+            .map(|map_entry| map_entry.info)
+            .filter(|info| tags.iter().all(|tag| info.metadata.tags.contains(tag)))
+            .filter(|info| search.as_ref().map(|search| map_matches_search(info, search)).unwrap_or(true))
+            .collect();
+
+        // Maps come sorted by name, which breaks ties
+        match maps_req.sort
+        {
+            payload::request::MapSort::Name => {},
+            payload::request::MapSort::Newest => maps_info.sort_by_key(|x| std::cmp::Reverse(x.uploaded_at)),
+            payload::request::MapSort::MostPlayed => maps_info.sort_by_key(|x| std::cmp::Reverse(x.times_played)),
+        }
+
+        let total = maps_info.len();
+        let maps_info = maps_info.into_iter().skip(maps_req.page.saturating_mul(page_size)).take(page_size).collect();
+
+        let response = payload::response::AvailableMaps{maps : maps_info, total};
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
     }
 
-    pub async fn download_map(download_map_req : payload::request::DownloadMap, maps_folder : String) -> Result<impl warp::Reply, warp::Rejection>
+    pub async fn download_map(download_map_req : payload::request::DownloadMap, map_catalog : MapCatalog) -> Result<impl warp::Reply, warp::Rejection>
     {
         let map = download_map_req.map_name; 
         
         // Check filename. Shouldn't contain slahes. can be a security issue
         if !is_map_name_valid(&map)
         {
-            let err = format!("Illegal character in map name");
-            return Ok(reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
         }
 
-        let map_file_name = map.clone() + ".zip";
-        let maps_folder_path = Path::new(&maps_folder);
-        let map_folder = get_map_folder(&map, &maps_folder);
-        let map_folder = Path::new(&map_folder);
-        //println!("Map path folder is {}", map_folder.to_str().unwrap());
+        let map_entry = match map_catalog.get(&map)
+        {
+            Ok(map_entry) => map_entry,
+            Err(err) => return Ok(error_reply(err)),
+        };
+        let version = download_map_req.map_version.unwrap_or_else(|| map_entry.info.map_version.clone());
+        let map_version = match map_entry.get_version(&version)
+        {
+            Some(map_version) => map_version,
+            None => {
+                let err = Error::new(ErrorCode::MapNotFound, format!("Could not find version {} of map {}", version, map));
+                return Ok(error_reply(err));
+            }
+        };
 
-        if map_folder.exists() && map_folder.is_dir()
+        if map_version.zip_size.is_some()
         {
-            let zip_path = maps_folder_path.join(map_file_name);
+            let zip_path = map_catalog.get_zip_path(&map, &version);
 
             // Create Zip File - This is no longer needed
             /*
@@ -322,126 +790,563 @@ pub mod handlers
             */
 
             // Read file, encode and write response
-            let mut buffer = Vec::new();
-            let mut file = File::open(zip_path).unwrap();
-            file.read_to_end(&mut buffer).unwrap();
+            let buffer = match std::fs::read(&zip_path)
+            {
+                Ok(buffer) => buffer,
+                Err(_) => {
+                    let err = Error::new(ErrorCode::InvalidMap, format!("Could not find zip file of map {}", map));
+                    return Ok(error_reply(err));
+                }
+            };
             let output = base64::encode(buffer);
 
-            let response = payload::response::DownloadMap{map : output};
+            let response = payload::response::DownloadMap{map : output, sha256 : map_version.zip_sha256.clone()};
             return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
         }
 
-        let err = format!("Could not find map with name {}", map);
-        return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+        let err = Error::new(ErrorCode::InvalidMap, format!("Could not find zip file of map {}", map));
+        return Ok(error_reply(err));
+    }
+
+    // GET /maps/{name}/{version}.zip
+    pub async fn get_map_zip(map : String, file_name : String, headers : HeaderMap, map_catalog : MapCatalog) -> Result<reply::Response, Infallible>
+    {
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err).into_response());
+        }
+
+        let map_entry = match map_catalog.get(&map)
+        {
+            Ok(map_entry) => map_entry,
+            Err(err) => return Ok(error_reply(err).into_response()),
+        };
+        let version = file_name.strip_suffix(".zip").unwrap_or_default();
+        let zip_sha256 = match map_entry.get_version(version)
+        {
+            Some(map_version) => map_version.zip_sha256.clone(),
+            None => {
+                let err = Error::new(ErrorCode::MapNotFound, format!("Could not find version {} of map {}", version, map));
+                return Ok(error_reply(err).into_response());
+            }
+        };
+
+        let zip_path = map_catalog.get_zip_path(&map, version);
+        let file = tokio::fs::File::open(&zip_path).await;
+        let metadata = match &file
+        {
+            Ok(file) => file.metadata().await,
+            Err(_) => Err(std::io::ErrorKind::NotFound.into()),
+        };
+        let (mut file, metadata) = match (file, metadata)
+        {
+            (Ok(file), Ok(metadata)) => (file, metadata),
+            _ => {
+                let err = Error::new(ErrorCode::InvalidMap, format!("Could not find zip file of map {}", map));
+                return Ok(error_reply(err).into_response());
+            }
+        };
+
+        // Let clients keep using the copy they already have
+        let etag = get_etag(zip_sha256.as_deref(), &metadata);
+        if is_not_modified(&headers, &etag)
+        {
+            let mut res = StatusCode::NOT_MODIFIED.into_response();
+            res.headers_mut().insert(header::ETAG, header_value(&etag));
+            return Ok(res);
+        }
+
+        let len = metadata.len();
+        let (status, start, end) = match get_range(&headers, &etag).map(|range| parse_range(range, len))
+        {
+            Some(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+            Some(None) => {
+                let err = Error::new(ErrorCode::InvalidRequest, format!("Range is not satisfiable. File has {} bytes", len));
+                let mut res = reply::with_status(reply::json(&err), StatusCode::RANGE_NOT_SATISFIABLE).into_response();
+                res.headers_mut().insert(header::CONTENT_RANGE, header_value(&format!("bytes */{}", len)));
+                return Ok(res);
+            },
+            None => (StatusCode::OK, 0, len.saturating_sub(1)),
+        };
+
+        if let Err(err) = file.seek(std::io::SeekFrom::Start(start)).await
+        {
+            return Ok(error_reply(Error::internal("Could not read map zip", err)).into_response());
+        }
+        let content_len = if len == 0 { 0 } else { end - start + 1 };
+        let stream = tokio_util::io::ReaderStream::new(file.take(content_len));
+
+        let mut res = reply::Response::new(warp::hyper::Body::wrap_stream(stream));
+        *res.status_mut() = status;
+        let res_headers = res.headers_mut();
+        res_headers.insert(header::CONTENT_TYPE, header_value("application/zip"));
+        res_headers.insert(header::CONTENT_LENGTH, header_value(&content_len.to_string()));
+        res_headers.insert(header::ACCEPT_RANGES, header_value("bytes"));
+        res_headers.insert(header::ETAG, header_value(&etag));
+        res_headers.insert(header::CONTENT_DISPOSITION, header_value(&format!("attachment; filename=\"{}.zip\"", map)));
+        if status == StatusCode::PARTIAL_CONTENT
+        {
+            res_headers.insert(header::CONTENT_RANGE, header_value(&format!("bytes {}-{}/{}", start, end, len)));
+        }
+
+        Ok(res)
+    }
+
+    // GET /maps/{name}/pictures/picture.jpg, or picture_{size}.jpg for its thumbnails
+    pub async fn get_map_picture_file(map : String, file_name : String, headers : HeaderMap, map_catalog : MapCatalog) -> Result<reply::Response, Infallible>
+    {
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err).into_response());
+        }
+
+        if let Err(err) = map_catalog.get(&map)
+        {
+            return Ok(error_reply(err).into_response());
+        }
+        let size = file_name.strip_prefix("picture_").and_then(|x| x.strip_suffix(".jpg"))
+            .and_then(|x| x.parse::<u32>().ok())
+            .filter(|x| maps::THUMBNAIL_SIZES.contains(x));
+        let pic_path = match (file_name.as_str(), size)
+        {
+            ("picture.jpg", _) | (_, Some(_)) => map_catalog.get_current_picture_path(&map, size),
+            _ => None,
+        };
+        let buffer = match pic_path
+        {
+            Some(pic_path) => tokio::fs::read(pic_path).await.ok(),
+            None => None,
+        };
+        let buffer = match buffer
+        {
+            Some(buffer) => buffer,
+            None => {
+                let err = Error::new(ErrorCode::MapPictureNotFound, format!("Could not find picture {} of map {}", file_name, map));
+                return Ok(error_reply(err).into_response());
+            }
+        };
+
+        let etag = format!("\"{:x}\"", Sha256::digest(&buffer));
+        let cache_control = format!("public, max-age={}", PICTURE_MAX_AGE.as_secs());
+        let mut res = if is_not_modified(&headers, &etag)
+        {
+            StatusCode::NOT_MODIFIED.into_response()
+        }
+        else
+        {
+            let mut res = reply::Response::new(buffer.into());
+            res.headers_mut().insert(header::CONTENT_TYPE, header_value("image/jpeg"));
+            res
+        };
+        res.headers_mut().insert(header::ETAG, header_value(&etag));
+        res.headers_mut().insert(header::CACHE_CONTROL, header_value(&cache_control));
+
+        Ok(res)
     }
 
-    pub async fn get_map_picture(map_picture_req : payload::request::MapPicture, maps_folder : String) -> Result<impl warp::Reply, Infallible>
+    pub async fn get_map_picture(map_picture_req : payload::request::MapPicture, map_catalog : MapCatalog) -> Result<impl warp::Reply, Infallible>
     {
         let map = map_picture_req.map_name; 
         
         // Check filename. Shouldn't contain slahes. can be a security issue
         if !is_map_name_valid(&map)
         {
-            let err = format!("Illegal character in map name");
-            return Ok(reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
         }
 
-        let pic_file_name =  map.clone() + ".jpg";
-        let map_folder = get_map_folder(&map, &maps_folder);
-        let map_folder = Path::new(&map_folder);
+        let map_entry = match map_catalog.get(&map)
+        {
+            Ok(map_entry) => map_entry,
+            Err(err) => return Ok(error_reply(err)),
+        };
 
-        if map_folder.exists() && map_folder.is_dir()
+        let sha256 = map_entry.info.picture_sha256.clone();
+        if let Some(pic_path) = map_catalog.get_current_picture_path(&map, None)
         {
-            let pic_path = map_folder.join(pic_file_name);
             if let Ok(mut pic_file) = std::fs::File::open(pic_path)
             {
                 let mut buffer = Vec::new();
-                pic_file.read_to_end(&mut buffer).unwrap();
+                if let Err(err) = pic_file.read_to_end(&mut buffer)
+                {
+                    return Ok(error_reply(Error::internal("Could not read map picture", err)));
+                }
                 
                 let b64 = base64::encode(buffer);
-                let response = payload::response::MapPicture{map_picture : b64};
+                let response = payload::response::MapPicture{map_picture : b64, sha256};
                 return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
             }
             else
             {
-                let err = format!("Could not find map picture");
-                return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+                let err = Error::new(ErrorCode::MapPictureNotFound, "Could not find map picture");
+                return Ok(error_reply(err));
             }
         }
 
-        let err = format!("Could not find map with name {}", map);
-        return Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND));
+        let err = Error::new(ErrorCode::MapPictureNotFound, "Could not find map picture");
+        return Ok(error_reply(err));
     }
 
-    pub async fn upload_map(upload_map_req : payload::request::UploadMap, maps_folder : String) -> Result<impl warp::Reply, warp::Rejection>
+    pub async fn upload_map(mut upload_map_req : payload::request::UploadMap, db : database::DB, map_catalog : MapCatalog) -> Result<impl warp::Reply, warp::Rejection>
     {
         // Check filename. Shouldn't contain slahes. can be a security issue
         let map = upload_map_req.map_name; 
         if !is_map_name_valid(&map)
         {
-            let err = format!("Illegal character in map name");
-            return Ok(reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
         }
 
         // Check if it's an update
-        let map_filename = map.clone() + ".zip";
-        let map_folder_path = maps_folder;
-        let maps_folder = Path::new(&map_folder_path);
-        let map_folder = maps_folder.join(&map);
-
-        let yml_path = map_folder.join(map.clone() + ".yml");
-        if map_folder.exists() && map_folder.is_dir()
+        let version = &upload_map_req.map_version;
+        let (password, metadata) = (&upload_map_req.password, &mut upload_map_req.metadata);
+        let res = check_map_access(&map_catalog, &map, password)
+            .and_then(|_| check_new_map_version(&map_catalog, &map, version))
+            .and_then(|_| maps::validate_map_metadata(metadata));
+        if let Err(err) = res
         {
-            let yml = read_map_yaml(&map, &map_folder_path);
-            let pass = yml["password"].as_str().unwrap();
+            return Ok(error_reply(err));
+        }
 
-            if pass != upload_map_req.password
+        if let Ok(buffer) = base64::decode(&upload_map_req.map_zip)
+        {
+            let res = get_map_access(&map_catalog, &map, password)
+                .and_then(|access| install_map_zip(&db, &map_catalog, &map, &access, version, metadata, &buffer));
+            if let Err(err) = res
             {
-                let err = format!("Password to update map {} was not correct", map);
-                return Ok(reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+                return Ok(error_reply(err));
             }
         }
+        else
+        {
+            let err = Error::new(ErrorCode::InvalidRequest, "Map zip was not valid base64");
+            return Ok(error_reply(err));
+        }
 
-        // Write map
-        let zip_path = maps_folder.join(map_filename);
-        //println!("Zip file path is {}", zip_path.to_str().unwrap());
-        if let Ok(buffer) = base64::decode(upload_map_req.map_zip)
+        let response = "Success";
+        return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+    }
+
+    pub async fn upload_map_picture(picture_req : payload::request::UploadMapPicture, map_catalog : MapCatalog) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let map = picture_req.map_name;
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        let (password, picture) = (&picture_req.password, &picture_req.picture);
+        let res = map_catalog.get(&map)
+            .and_then(|_| check_map_access(&map_catalog, &map, password))
+            .and_then(|_| base64::decode(picture).map_err(|_| Error::new(ErrorCode::InvalidRequest, "Picture was not valid base64")))
+            .and_then(|buffer| map_catalog.install_picture(&map, &buffer));
+        if let Err(err) = res
         {
-            // Create dir
-            if !map_folder.exists()
+            return Ok(error_reply(err));
+        }
+
+        let response = "Success";
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn rollback_map(rollback_req : payload::request::RollbackMap, map_catalog : MapCatalog) -> Result<impl warp::Reply, Infallible>
+    {
+        let map = rollback_req.map_name;
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        let (password, version) = (&rollback_req.password, &rollback_req.map_version);
+        let res = map_catalog.get(&map)
+            .and_then(|_| check_map_access(&map_catalog, &map, password))
+            .and_then(|_| map_catalog.set_current_version(&map, version));
+        if let Err(err) = res
+        {
+            return Ok(error_reply(err));
+        }
+
+        let response = "Success";
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn delete_map(delete_map_req : payload::request::DeleteMap, db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let map = delete_map_req.map_name;
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        let is_admin = is_admin_key(&admin_key, delete_map_req.admin_key.as_deref());
+        let res = check_map_management(&map_catalog, &map, &delete_map_req.password, is_admin)
+            .and_then(|_| {
+                let _guard = map_catalog.lock_use();
+                check_map_not_in_use(&db, &map).and_then(|_| map_catalog.remove_map(&map))
+            });
+        if let Err(err) = res
+        {
+            return Ok(error_reply(err));
+        }
+
+        let response = "Success";
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn rename_map(rename_map_req : payload::request::RenameMap, db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let (map, new_name) = (rename_map_req.map_name, rename_map_req.new_map_name);
+        if !is_map_name_valid(&map) || !is_map_name_valid(&new_name)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        let is_admin = is_admin_key(&admin_key, rename_map_req.admin_key.as_deref());
+        let res = check_map_management(&map_catalog, &map, &rename_map_req.password, is_admin)
+            .and_then(|_| {
+                let _guard = map_catalog.lock_use();
+                check_map_not_in_use(&db, &map).and_then(|_| map_catalog.rename_map(&map, &new_name))
+            });
+        if let Err(err) = res
+        {
+            return Ok(error_reply(err));
+        }
+
+        let response = "Success";
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn admin_list_maps(list_req : payload::request::AdminListMaps, db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        if !is_admin_key(&admin_key, Some(&list_req.admin_key))
+        {
+            let err = Error::new(ErrorCode::NotAdmin, "Admin key was not correct");
+            return Ok(error_reply(err));
+        }
+
+        let maps : Vec<payload::response::AdminMapInfo> = map_catalog.get_all_results().into_iter().map(|(map, entry)| {
+            let (entry, error) = match entry
             {
-                std::fs::create_dir_all(&map_folder).unwrap();
+                Ok(entry) => (Some(entry), None),
+                Err(err) => (None, Some(err.message)),
+            };
+            payload::response::AdminMapInfo{
+                map_version : entry.as_ref().map(|x| x.info.map_version.clone()),
+                versions : entry.as_ref().map(|x| x.info.versions.clone()).unwrap_or_default(),
+                has_password : entry.as_ref().map(|x| !x.access.password_hash.is_empty()).unwrap_or(false),
+                disk_usage : map_catalog.get_disk_usage(&map),
+                games : get_map_versions_in_use(&db, &map).len(),
+                error,
+                map_name : map,
             }
-            
-            // Write zip file
+        }).collect();
+        let disk_usage = maps.iter().map(|x| x.disk_usage).sum();
+
+        let response = payload::response::AdminMapList{maps, disk_usage};
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn start_map_upload(mut start_upload_req : payload::request::StartMapUpload, db : database::DB, map_catalog : MapCatalog, 
+        address : Option<std::net::SocketAddr>) -> Result<impl warp::Reply, Infallible>
+    {
+        let map = start_upload_req.map_name;
+        if !is_map_name_valid(&map)
+        {
+            let err = Error::new(ErrorCode::InvalidMapName, "Illegal character in map name");
+            return Ok(error_reply(err));
+        }
+
+        let version = &start_upload_req.map_version;
+        let metadata = &mut start_upload_req.metadata;
+        let res = check_map_access(&map_catalog, &map, &start_upload_req.password)
+            .and_then(|_| check_new_map_version(&map_catalog, &map, version))
+            .and_then(|_| maps::validate_map_metadata(metadata));
+        if let Err(err) = res
+        {
+            return Ok(error_reply(err));
+        }
+
+        if start_upload_req.size == 0 || start_upload_req.size > MAX_MAP_UPLOAD_SIZE
+        {
+            let err = Error::new(ErrorCode::PayloadTooLarge, format!("Map zips can have between 1 and {} bytes", MAX_MAP_UPLOAD_SIZE));
+            return Ok(error_reply(err.with_details(serde_json::json!({"max_size" : MAX_MAP_UPLOAD_SIZE}))));
+        }
+
+        let sha256 = start_upload_req.sha256.to_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|x| x.is_ascii_hexdigit())
+        {
+            let err = Error::new(ErrorCode::InvalidRequest, "Hash must be a SHA-256 in hex");
+            return Ok(error_reply(err));
+        }
+
+        let ip = address.map(|x| x.ip());
+        if let Err(err) = check_upload_quota(&db, ip, start_upload_req.size)
+        {
+            return Ok(error_reply(err));
+        }
+
+        let upload = entity::MapUpload::new(map, start_upload_req.password, ip, start_upload_req.map_version, 
+            start_upload_req.metadata, start_upload_req.size, sha256);
+        if let Err(err) = std::fs::create_dir_all(map_catalog.get_upload_folder(&upload.id))
+        {
+            return Ok(error_reply(Error::internal("Could not create upload folder", err)));
+        }
+        db.map_upload_table.insert(upload.id, upload.clone());
+
+        let response = get_map_upload_info(&map_catalog, &upload);
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK))
+    }
+
+    pub async fn upload_map_chunk(chunk_req : payload::request::UploadMapChunk, db : database::DB, map_catalog : MapCatalog) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        let upload_id = chunk_req.upload_id;
+        if let Some(mut upload) = db.map_upload_table.get(&upload_id)
+        {
+            let chunk_count = get_chunk_count(upload.size);
+            if chunk_req.index >= chunk_count
             {
-                let mut file = File::create(&zip_path).unwrap();
-                file.write_all(&buffer).unwrap();
+                let err = Error::new(ErrorCode::InvalidRequest, format!("Chunk index must be lower than {}", chunk_count));
+                return Ok(error_reply(err));
+            }
+
+            let data = match base64::decode(chunk_req.data)
+            {
+                Ok(data) => data,
+                Err(_) => {
+                    let err = Error::new(ErrorCode::InvalidRequest, "Chunk data was not valid base64");
+                    return Ok(error_reply(err));
+                }
+            };
+            let expected_size = get_chunk_size(upload.size, chunk_req.index);
+            if data.len() as u64 != expected_size
+            {
+                let err = Error::new(ErrorCode::InvalidRequest, format!("Chunk {} must have {} bytes", chunk_req.index, expected_size));
+                return Ok(error_reply(err));
+            }
+
+            // Chunks are written under a temp name, so a chunk which is on disk is always complete
+            let folder = map_catalog.get_upload_folder(&upload_id);
+            let part_path = folder.join(format!("{}.part", chunk_req.index));
+            let chunk_path = folder.join(chunk_req.index.to_string());
+            let res = std::fs::write(&part_path, &data).and_then(|_| std::fs::rename(&part_path, chunk_path));
+            if let Err(err) = res
+            {
+                return Ok(error_reply(Error::internal("Could not write upload chunk", err)));
+            }
+
+            upload.last_update = get_unix_time_ms();
+            db.map_upload_table.insert(upload_id, upload.clone());
+
+            let response = get_map_upload_info(&map_catalog, &upload);
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+        }
+
+        let err = Error::new(ErrorCode::UploadNotFound, format!("Could not find upload with id {}", upload_id));
+        Ok(error_reply(err))
+    }
+
+    // Tells clients which chunks they still have to send, so they can resume an upload
+    pub async fn get_map_upload(upload_req : payload::request::MapUpload, db : database::DB, map_catalog : MapCatalog) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        if let Some(upload) = db.map_upload_table.get(&upload_req.upload_id)
+        {
+            let response = get_map_upload_info(&map_catalog, &upload);
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+        }
+
+        let err = Error::new(ErrorCode::UploadNotFound, format!("Could not find upload with id {}", upload_req.upload_id));
+        Ok(error_reply(err))
+    }
+
+    pub async fn commit_map_upload(upload_req : payload::request::MapUpload, db : database::DB, map_catalog : MapCatalog) 
+        -> Result<impl warp::Reply, Infallible>
+    {
+        // Taking the upload out of the table prevents it from being committed twice at the same time
+        let upload_id = upload_req.upload_id;
+        if let Some(upload) = db.map_upload_table.remove(&upload_id)
+        {
+            let missing_chunks = get_missing_chunks(&map_catalog, &upload);
+            if !missing_chunks.is_empty()
+            {
+                db.map_upload_table.insert(upload_id, upload);
+                let err = Error::new(ErrorCode::UploadIncomplete, format!("Upload is missing {} chunks", missing_chunks.len()));
+                return Ok(error_reply(err.with_details(serde_json::json!({"missing_chunks" : missing_chunks}))));
+            }
+
+            let res = commit_map_upload_fn(&db, &map_catalog, upload);
+            remove_upload_folder(&map_catalog, &upload_id);
+            if let Err(err) = res
+            {
+                return Ok(error_reply(err));
             }
-            
-            // Extract zip
-            let file = std::fs::File::open(&zip_path).unwrap();
-            zip_extract(file, &maps_folder.to_str().unwrap().to_string());
 
-            // Create config file
-            let mut output = String::new();
-            let mut hash = Hash::new();
-            hash.insert(yaml_rust::Yaml::String("password".to_string()), yaml_rust::Yaml::String(upload_map_req.password));
-            hash.insert(yaml_rust::Yaml::String("version".to_string()), yaml_rust::Yaml::String(upload_map_req.map_version));
+            let response = "Success";
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+        }
 
-            let gamemodes = upload_map_req.supported_gamemodes.iter().map(|x| yaml_rust::Yaml::String(x.to_string())).collect();
-            hash.insert(yaml_rust::Yaml::String("gamemodes".to_string()), yaml_rust::Yaml::Array(gamemodes));
-            let mut emmiter = YamlEmitter::new(&mut output);
-            emmiter.dump(&yaml_rust::Yaml::Hash(hash)).unwrap();
+        let err = Error::new(ErrorCode::UploadNotFound, format!("Could not find upload with id {}", upload_id));
+        Ok(error_reply(err))
+    }
 
+    // Called periodically. Removes the uploads which haven't received chunks in a while
+    pub fn remove_stale_uploads(db : &database::DB, map_catalog : &MapCatalog)
+    {
+        let now = get_unix_time_ms();
+        for upload in db.map_upload_table.get_all()
+        {
+            if now.saturating_sub(upload.last_update) >= MAP_UPLOAD_TIMEOUT.as_millis() as u64
             {
-                let mut file = File::create(&yml_path).unwrap();
-                file.write(output.as_bytes()).unwrap();
+                db.map_upload_table.remove(&upload.id);
+                remove_upload_folder(map_catalog, &upload.id);
             }
         }
+    }
 
-        let response = "Success";
-        return Ok(reply::with_status(reply::json(&response), StatusCode::OK));
+    pub async fn list_hosts(db : database::DB) -> Result<impl warp::Reply, Infallible>
+    {
+        let hosts = db.host_table.get_all().into_iter()
+            .map(|host| payload::response::HostInfo{id : host.id, name : host.name, region : host.region, address : host.address, ping_port : host.ping_port})
+            .collect();
+
+        let response = payload::response::ListHosts{hosts};
+        Ok(reply::json(&response))
+    }
+
+    pub async fn report_ping(report_ping_req : payload::request::ReportPing, db : database::DB) -> Result<impl warp::Reply, Infallible>
+    {
+        let player_id = report_ping_req.player_id;
+        if db.player_table.get(&player_id).is_some()
+        {
+            let mut player_ping = db.player_ping_table.get(&player_id).unwrap_or_default();
+            for host_ping in report_ping_req.pings
+            {
+                if db.host_table.get(&host_ping.host_id).is_none()
+                {
+                    let err = Error::new(ErrorCode::HostNotFound, format!("Could not find host with id {}", host_ping.host_id));
+                    return Ok(error_reply(err));
+                }
+
+                player_ping.pings.insert(host_ping.host_id, host_ping.ping);
+            }
+            db.player_ping_table.insert(player_id, player_ping);
+
+            return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
+        }
+
+        let err = Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", player_id));
+        Ok(error_reply(err))
     }
 
     pub async fn notify_server_event(server_event : payload::request::NotifyServerEvent, db : database::DB) -> Result<impl warp::Reply, Infallible>
@@ -458,207 +1363,943 @@ pub mod handlers
                 {
                     ServerEvent::PlayerLeft{player_id} => {
                         println!("Player with id {} left game {}", player_id, game_id);
-                        leave_game_fn(&db, player_id);
+                        // Servers only know the public ids they were launched with
+                        match get_player_id(&db, &player_id)
+                        {
+                            Ok(player_id) => leave_game_fn(&db, player_id),
+                            Err(err) => return Ok(error_reply(err)),
+                        }
                     },
                     ServerEvent::GameEnded => { 
                         println!("Game {} is over", game_id);
-                        set_game_state(&db, &game_id, GameState::InLobby);
+                        if let Err(err) = set_game_state(&db, &game_id, GameState::PostGame)
+                        {
+                            let err = Error::new(ErrorCode::InvalidGameState, format!("Game {} could not go from {:?} to {:?}", game_id, err.from, err.to));
+                            return Ok(error_reply(err));
+                        }
+                        post_system_msg(&db, &game_id, "Game is over".to_string());
+                        notify_game_update(&db, &game_id);
                     },
                 }
 
-                return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
+                return Ok(reply::with_status(reply::json(&"".to_string()), StatusCode::OK));
+            }
+
+            let err = Error::new(ErrorCode::WrongServerKey, format!("Key was not correct for game with id {}", server_event.game_id));
+            return Ok(error_reply(err));
+        }
+
+        let err = Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", server_event.game_id));
+        Ok(error_reply(err))
+    }
+
+
+    // Helper Functions
+
+    // Existing maps can only be updated with the password they were uploaded with, so new maps need one
+    fn check_map_access(map_catalog : &MapCatalog, map : &str, password : &str) -> Result<(), Error>
+    {
+        match map_catalog.get(map)
+        {
+            Ok(map_entry) => {
+                if !map_entry.access.is_allowed(password)
+                {
+                    return Err(Error::new(ErrorCode::WrongPassword, format!("Password to update map {} was not correct", map)));
+                }
+                Ok(())
+            },
+            // A map without a valid config can't be claimed by anyone
+            Err(err) if map_catalog.get_map_folder(map).exists() => Err(err),
+            Err(_) => {
+                if password.is_empty()
+                {
+                    return Err(Error::new(ErrorCode::InvalidRequest, "New maps need a password"));
+                }
+                Ok(())
+            },
+        }
+    }
+
+    // Games are played in a mode of the registry which their map supports. Their max players must fit both the mode
+    // and the player amounts of the map, if it has them. Spectators are capped independently of the mode
+    fn check_game_settings(map_info : &payload::response::MapInfo, name : &str, mode : &str, max_players : u8, max_spectators : u8) 
+        -> Result<&'static modes::ModeInfo, Error>
+    {
+        let name_len = name.trim().chars().count();
+        if name_len == 0 || name_len > MAX_GAME_NAME_LEN || name.chars().any(|x| x.is_control())
+        {
+            let err = Error::new(ErrorCode::InvalidGameName, format!("Game names must have between 1 and {} characters", MAX_GAME_NAME_LEN));
+            return Err(err.with_details(serde_json::json!({"max_len" : MAX_GAME_NAME_LEN})));
+        }
+
+        let mode_info = match modes::get_mode(mode)
+        {
+            Some(mode_info) => mode_info,
+            None => {
+                let err = Error::new(ErrorCode::InvalidGameMode, format!("Could not find game mode {}", mode));
+                let names : Vec<&str> = modes::MODES.iter().map(|x| x.name).collect();
+                return Err(err.with_details(serde_json::json!({"modes" : names})));
+            }
+        };
+
+        let metadata = &map_info.metadata;
+        if !metadata.supported_gamemodes.iter().any(|x| x.eq_ignore_ascii_case(mode_info.name))
+        {
+            let err = Error::new(ErrorCode::ModeNotSupported, format!("Map {} does not support mode {}", map_info.map_name, mode_info.name));
+            return Err(err.with_details(serde_json::json!({"supported_gamemodes" : metadata.supported_gamemodes})));
+        }
+
+        let min = std::cmp::max(mode_info.min_players, metadata.min_players.unwrap_or(0));
+        let max = std::cmp::min(mode_info.max_players, metadata.max_players.unwrap_or(u8::MAX));
+        if min > max
+        {
+            // No amount of players fits both, so the map can't be played in this mode
+            let msg = format!("Map {} needs between {} and {} players, which mode {} doesn't allow", map_info.map_name, 
+                metadata.min_players.unwrap_or(0), metadata.max_players.unwrap_or(u8::MAX), mode_info.name);
+            let err = Error::new(ErrorCode::ModeNotSupported, msg);
+            return Err(err.with_details(serde_json::json!({"supported_gamemodes" : metadata.supported_gamemodes})));
+        }
+        if max_players < min || max_players > max
+        {
+            let msg = format!("Max players must be between {} and {} for mode {} on map {}", min, max, mode_info.name, map_info.map_name);
+            let err = Error::new(ErrorCode::InvalidMaxPlayers, msg);
+            return Err(err.with_details(serde_json::json!({"min" : min, "max" : max})));
+        }
+
+        if max_spectators > MAX_SPECTATORS
+        {
+            let msg = format!("Max spectators must be between 0 and {}", MAX_SPECTATORS);
+            let err = Error::new(ErrorCode::InvalidMaxSpectators, msg);
+            return Err(err.with_details(serde_json::json!({"min" : 0, "max" : MAX_SPECTATORS})));
+        }
+
+        Ok(mode_info)
+    }
+
+    fn map_matches_search(info : &payload::response::MapInfo, search : &str) -> bool
+    {
+        let metadata = &info.metadata;
+        info.map_name.to_lowercase().contains(search) || metadata.author.to_lowercase().contains(search)
+            || metadata.description.to_lowercase().contains(search) || metadata.tags.iter().any(|tag| tag.contains(search))
+    }
+
+    // Deleting or renaming a map is allowed to anyone with its password, and to admins.
+    // Admins can manage maps which failed to load too
+    fn check_map_management(map_catalog : &MapCatalog, map : &str, password : &str, is_admin : bool) 
+        -> Result<(), Error>
+    {
+        if is_admin && map_catalog.get_map_folder(map).is_dir()
+        {
+            return Ok(());
+        }
+
+        let map_entry = map_catalog.get(map)?;
+        if !is_admin && !map_entry.access.is_allowed(password)
+        {
+            return Err(Error::new(ErrorCode::WrongPassword, format!("Password to manage map {} was not correct", map)));
+        }
+        Ok(())
+    }
+
+    // Games launch their map from its folder, so it can't go away while they're alive
+    fn check_map_not_in_use(db : &database::DB, map : &str) -> Result<(), Error>
+    {
+        let games = get_map_versions_in_use(db, map).len();
+        if games > 0
+        {
+            let err = Error::new(ErrorCode::MapInUse, format!("Map {} is being used by {} games", map, games));
+            return Err(err.with_details(serde_json::json!({"games" : games})));
+        }
+        Ok(())
+    }
+
+    // Keys are hashed first, so the comparison takes the same time whatever their length and contents
+    fn is_admin_key(admin_key : &Option<String>, key : Option<&str>) -> bool
+    {
+        match (admin_key, key)
+        {
+            (Some(admin_key), Some(key)) => {
+                let (expected, actual) = (Sha256::digest(admin_key.as_bytes()), Sha256::digest(key.as_bytes()));
+                expected.iter().zip(actual.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+            },
+            _ => false,
+        }
+    }
+
+    // Updates keep the map's password
+    fn get_map_access(map_catalog : &MapCatalog, map : &str, password : &str) -> Result<maps::MapAccess, Error>
+    {
+        match map_catalog.get(map)
+        {
+            Ok(map_entry) => Ok(map_entry.access),
+            Err(_) => maps::MapAccess::new(password),
+        }
+    }
+
+    // Uploaded versions can't be replaced, so games which use them keep getting the same files
+    fn check_new_map_version(map_catalog : &MapCatalog, map : &str, version : &str) -> Result<(), Error>
+    {
+        if !maps::is_map_version_valid(version)
+        {
+            return Err(Error::new(ErrorCode::InvalidMapVersion, "Illegal character in map version"));
+        }
+        if map_catalog.get_version_folder(map, version).exists()
+        {
+            return Err(maps::version_exists_error(map, version));
+        }
+
+        Ok(())
+    }
+
+    fn install_map_zip(db : &database::DB, map_catalog : &MapCatalog, map : &str, access : &maps::MapAccess, map_version : &str, 
+        metadata : &MapMetadata, buffer : &[u8]) -> Result<(), Error>
+    {
+        // Check archive
+        let mut archive = zip::ZipArchive::new(Cursor::new(buffer))
+            .map_err(|err| Error::new(ErrorCode::InvalidMap, format!("Map archive is invalid: {}", err)))?;
+        maps::validate_map_archive(map, &mut archive)?;
+
+        // Write map
+        map_catalog.install_map(map, map_version, &mut archive, buffer, metadata, access)?;
+
+        // Don't wait for the watcher, so the map is available as soon as we reply
+        map_catalog.reload_map(map);
+        map_catalog.remove_old_versions(map, &get_map_versions_in_use(db, map));
+        Ok(())
+    }
+
+    // Versions pinned by games which haven't been closed yet
+    fn get_map_versions_in_use(db : &database::DB, map : &str) -> Vec<String>
+    {
+        db.game_table.get_all().into_iter()
+            .filter(|game| game.map == map && !matches!(game.state, GameState::Closed))
+            .map(|game| game.map_version)
+            .collect()
+    }
+
+    fn commit_map_upload_fn(db : &database::DB, map_catalog : &MapCatalog, upload : entity::MapUpload) -> Result<(), Error>
+    {
+        // Access is checked again, in case someone else uploaded the map in the meantime
+        check_map_access(map_catalog, &upload.map_name, &upload.password)?;
+
+        let folder = map_catalog.get_upload_folder(&upload.id);
+        let mut buffer = Vec::with_capacity(upload.size as usize);
+        for index in 0..get_chunk_count(upload.size)
+        {
+            let mut chunk = std::fs::read(folder.join(index.to_string())).map_err(|err| Error::internal("Could not read upload chunk", err))?;
+            buffer.append(&mut chunk);
+        }
+
+        let sha256 = format!("{:x}", Sha256::digest(&buffer));
+        if sha256 != upload.sha256
+        {
+            return Err(Error::new(ErrorCode::HashMismatch, format!("Uploaded file has hash {}, expected {}", sha256, upload.sha256)));
+        }
+
+        let access = get_map_access(map_catalog, &upload.map_name, &upload.password)?;
+        install_map_zip(db, map_catalog, &upload.map_name, &access, &upload.map_version, &upload.metadata, &buffer)?;
+        Ok(())
+    }
+
+    // Each upload may take up to MAX_MAP_UPLOAD_SIZE on disk until it's committed or times out
+    fn check_upload_quota(db : &database::DB, ip : Option<std::net::IpAddr>, size : u64) -> Result<(), Error>
+    {
+        let uploads = db.map_upload_table.get_all();
+        let client_uploads = uploads.iter().filter(|upload| ip.is_some() && upload.ip == ip).count();
+        if client_uploads >= MAX_UPLOADS_PER_IP
+        {
+            let err = Error::new(ErrorCode::TooManyUploads, format!("Clients can have up to {} uploads at the same time", MAX_UPLOADS_PER_IP));
+            return Err(err.with_details(serde_json::json!({"max_uploads" : MAX_UPLOADS_PER_IP})));
+        }
+
+        let staged_size : u64 = uploads.iter().map(|upload| upload.size).sum();
+        if staged_size + size > MAX_STAGED_UPLOAD_SIZE
+        {
+            return Err(Error::new(ErrorCode::TooManyUploads, "Too many maps are being uploaded. Try again later"));
+        }
+
+        Ok(())
+    }
+
+    fn get_map_upload_info(map_catalog : &MapCatalog, upload : &entity::MapUpload) -> payload::response::MapUpload
+    {
+        payload::response::MapUpload{
+            upload_id : upload.id,
+            chunk_size : MAP_UPLOAD_CHUNK_SIZE,
+            chunk_count : get_chunk_count(upload.size),
+            missing_chunks : get_missing_chunks(map_catalog, upload),
+        }
+    }
+
+    fn get_missing_chunks(map_catalog : &MapCatalog, upload : &entity::MapUpload) -> Vec<u64>
+    {
+        let folder = map_catalog.get_upload_folder(&upload.id);
+        (0..get_chunk_count(upload.size)).filter(|index| !folder.join(index.to_string()).is_file()).collect()
+    }
+
+    fn get_chunk_count(size : u64) -> u64
+    {
+        size.div_ceil(MAP_UPLOAD_CHUNK_SIZE)
+    }
+
+    fn get_chunk_size(size : u64, index : u64) -> u64
+    {
+        std::cmp::min(MAP_UPLOAD_CHUNK_SIZE, size - index * MAP_UPLOAD_CHUNK_SIZE)
+    }
+
+    fn remove_upload_folder(map_catalog : &MapCatalog, upload_id : &uuid::Uuid)
+    {
+        if let Err(err) = std::fs::remove_dir_all(map_catalog.get_upload_folder(upload_id))
+        {
+            println!("Could not remove folder of upload {}: {}", upload_id, err);
+        }
+    }
+
+    // The hash of the file, so it can be checked against the one in MapInfo.
+    // Otherwise, one which changes whenever the file is written again
+    fn get_etag(sha256 : Option<&str>, metadata : &std::fs::Metadata) -> String
+    {
+        if let Some(sha256) = sha256
+        {
+            return format!("\"{}\"", sha256);
+        }
+
+        let modified = metadata.modified().ok()
+            .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|x| x.as_nanos()).unwrap_or_default();
+        format!("\"{:x}-{:x}\"", metadata.len(), modified)
+    }
+
+    // Whether the client already has the version of the file with this ETag
+    fn is_not_modified(headers : &HeaderMap, etag : &str) -> bool
+    {
+        let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|x| x.to_str().ok());
+        if_none_match.map(|x| x.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")).unwrap_or(false)
+    }
+
+    // Ranges only apply if the file hasn't changed since the client got the first part.
+    // Requests for several ranges get the whole file instead
+    fn get_range<'a>(headers : &'a HeaderMap, etag : &str) -> Option<&'a str>
+    {
+        let if_range = headers.get(header::IF_RANGE).and_then(|x| x.to_str().ok());
+        headers.get(header::RANGE).and_then(|x| x.to_str().ok())
+            .filter(|x| !x.contains(','))
+            .filter(|_| if_range.map(|x| x == etag).unwrap_or(true))
+    }
+
+    // Parses a single byte range, as in "bytes=0-99", "bytes=100-" or "bytes=-100".
+    // Returns the first and last byte, or None if the range can't be served
+    fn parse_range(range : &str, len : u64) -> Option<(u64, u64)>
+    {
+        let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        let (start, end) = if start.is_empty()
+        {
+            let suffix : u64 = end.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len.checked_sub(1)?)
+        }
+        else
+        {
+            let start : u64 = start.parse().ok()?;
+            let end = if end.is_empty() { len.checked_sub(1)? } else { end.parse::<u64>().ok()?.min(len.checked_sub(1)?) };
+            (start, end)
+        };
+
+        Some((start, end)).filter(|(start, end)| start <= end)
+    }
+
+    fn header_value(value : &str) -> HeaderValue
+    {
+        HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static(""))
+    }
+
+    fn _zip_dir<T>(it: &mut dyn Iterator<Item=DirEntry>, prefix: &str, writer: T, method: zip::CompressionMethod)
+              -> zip::result::ZipResult<()> where T: Write+Seek
+    {
+        let mut zip = zip::ZipWriter::new(writer);
+        let options = FileOptions::default().compression_method(method).unix_permissions(0o755);
+        
+        for entry in it {
+            let path = entry.path();
+            let name = path.strip_prefix(Path::new(prefix)).unwrap();
+
+            if path.is_file() {
+                zip.start_file(name.to_str().unwrap(), options)?;
+                let mut f = File::open(path)?;
+                
+                let mut buffer = Vec::new();
+                f.read_to_end(&mut buffer)?;
+                zip.write_all(&buffer)?;
+
+                buffer.clear();
+            } else if !name.as_os_str().is_empty() {
+
+                zip.add_directory(name.to_str().unwrap(), options)?;
+            }
+        }
+
+        zip.finish()?;
+        Result::Ok(())
+    }
+
+    #[derive(Debug)]
+    enum JoinGameError
+    {
+        GameFull,
+        PlayerNotFound,
+        GameNotFound,
+        PlayerBanned,
+        NotJoinable,
+    }
+
+    fn join_game_as(db : &database::DB, player_id : uuid::Uuid, game_id : uuid::Uuid, player_type : PlayerType) -> Result<entity::PlayerGame, JoinGameError>
+    {
+        let spectator = matches!(player_type, PlayerType::Spectator);
+        let is_full = if spectator {is_spectators_full(db, &game_id)} else {is_game_full(db, &game_id)};
+        if let Ok(full) = is_full
+        {
+            let game = db.game_table.get(&game_id).ok_or(JoinGameError::GameNotFound)?;
+            if game.banned.contains(&player_id)
+            {
+                return Err(JoinGameError::PlayerBanned);
+            }
+            // Servers are launched with a fixed list of players, so games past the lobby only take spectators
+            let joinable = if spectator { game.state.is_joinable() } else { game.state.is_lobby() };
+            if !joinable
+            {
+                return Err(JoinGameError::NotJoinable);
+            }
+
+            if !full {
+                // Find player
+                if let Some(player) = db.player_table.get(&player_id)
+                {
+                    // Insert new entry
+                    let mut player_game = match player_type {
+                        PlayerType::Host => entity::PlayerGame::new_host(player_id, game_id),
+                        PlayerType::Spectator => entity::PlayerGame::new_spectator(player_id, game_id),
+                        PlayerType::Player(_) => entity::PlayerGame::new_player(player_id, game_id),
+                    };
+                    if !spectator && modes::get_team_count(&game.mode) > 0
+                    {
+                        player_game.team = Some(get_free_team(db, &game).ok_or(JoinGameError::GameFull)?);
+                    }
+                    db.player_game_table.insert(player_id, player_game.clone());
+                    match player_type {
+                        PlayerType::Host => {},
+                        PlayerType::Spectator => post_system_msg(db, &game_id, format!("{} is now spectating", player.name)),
+                        PlayerType::Player(_) => post_system_msg(db, &game_id, format!("{} joined the game", player.name)),
+                    }
+
+                    return Ok(player_game);
+                }
+                return Err(JoinGameError::PlayerNotFound);
+            }
+            return Err(JoinGameError::GameFull);
+        }
+        Err(JoinGameError::GameNotFound)
+    }
+    enum LaunchServerError
+    {
+        CouldNotlaunch,
+        GameNotFound,
+        HostNotFound,
+        InvalidState,
+    }
+
+    // Kicks or bans the target from the host's game
+    fn remove_player_as_host(db : &database::DB, host_id : uuid::Uuid, target_id : uuid::Uuid, ban : bool) -> Result<(), Error>
+    {
+        let mut game = get_lobby_as_host(db, &host_id)?;
+        if host_id == target_id
+        {
+            return Err(Error::new(ErrorCode::InvalidTarget, "Host cannot remove itself"));
+        }
+
+        let game_id = game.id;
+
+        let target = db.player_table.get(&target_id)
+            .ok_or_else(|| Error::new(ErrorCode::PlayerNotFound, "Could not find target player"))?;
+        let target_in_game = db.player_game_table.get(&target_id).map(|entry| entry.game_id == game_id).unwrap_or(false);
+        if !target_in_game
+        {
+            return Err(Error::new(ErrorCode::PlayerNotInGame, format!("Could not find player {} in game with id {}", target.public_id, game_id)));
+        }
+
+        db.player_game_table.remove(&target_id);
+        if ban
+        {
+            game.banned.push(target_id);
+            db.game_table.insert(game_id, game);
+            post_system_msg(db, &game_id, format!("{} was banned by the host", target.name));
+        }
+        else
+        {
+            post_system_msg(db, &game_id, format!("{} was kicked by the host", target.name));
+        }
+        cancel_auto_start(db, &game_id);
+
+        notify_game_update(db, &game_id);
+        Ok(())
+    }
+
+    // Session id of the player with the given public id
+    fn get_player_id(db : &database::DB, public_id : &uuid::Uuid) -> Result<uuid::Uuid, Error>
+    {
+        db.player_table.get_all().into_iter()
+            .find(|player| player.public_id == *public_id)
+            .map(|player| player.id)
+            .ok_or_else(|| Error::new(ErrorCode::PlayerNotFound, format!("Could not find player with id {}", public_id)))
+    }
+
+    // Returns the game hosted by the player, as long as it's in lobby
+    fn get_lobby_as_host(db : &database::DB, player_id : &uuid::Uuid) -> Result<entity::Game, Error>
+    {
+        let player_game = db.player_game_table.get(player_id)
+            .ok_or_else(|| Error::new(ErrorCode::PlayerNotInGame, format!("Player was not in a game {}", player_id)))?;
+        if !matches!(player_game.player_type, PlayerType::Host)
+        {
+            return Err(Error::new(ErrorCode::NotHost, "Player was not host"));
+        }
+
+        let game = db.game_table.get(&player_game.game_id)
+            .ok_or_else(|| Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", player_game.game_id)))?;
+        if !game.state.is_lobby()
+        {
+            return Err(Error::new(ErrorCode::InvalidGameState, "Game was not in lobby"));
+        }
+
+        Ok(game)
+    }
+
+    fn get_team_sizes(db : &database::DB, game : &entity::Game) -> Vec<u8>
+    {
+        let mut sizes = vec![0; modes::get_team_count(&game.mode) as usize];
+        for entry in get_game_entries(db, &game.id)
+        {
+            if let Some(size) = entry.team.and_then(|team| sizes.get_mut(team as usize))
+            {
+                *size += 1;
+            }
+        }
+
+        sizes
+    }
+
+    // Smallest team which still has room
+    fn get_free_team(db : &database::DB, game : &entity::Game) -> Option<u8>
+    {
+        let cap = modes::get_team_cap(&game.mode, game.max_players);
+        get_team_sizes(db, game).into_iter().enumerate()
+            .filter(|(_team, size)| *size < cap)
+            .min_by_key(|(team, size)| (*size, *team))
+            .map(|(team, _size)| team as u8)
+    }
+
+    // Deals every player into the mode's teams, either in join order or randomly
+    fn assign_teams(db : &database::DB, game : &entity::Game, shuffle : bool)
+    {
+        let teams = modes::get_team_count(&game.mode);
+        let mut entries : Vec<entity::PlayerGame> = get_game_entries(db, &game.id).into_iter()
+            .filter(|entry| !matches!(entry.player_type, PlayerType::Spectator))
+            .collect();
+        entries.sort_by_key(|entry| (entry.joined_at, entry.player_id));
+        if shuffle
+        {
+            entries.shuffle(&mut rand::thread_rng());
+        }
+
+        for (index, mut entry) in entries.into_iter().enumerate()
+        {
+            entry.team = if teams > 0 { Some((index % teams as usize) as u8) } else { None };
+            db.player_game_table.insert(entry.player_id, entry);
+        }
+    }
+
+    // Moves the last players to join the biggest team to the smallest one, until team sizes are even
+    fn balance_game_teams(db : &database::DB, game : &entity::Game)
+    {
+        loop
+        {
+            let sizes = get_team_sizes(db, game);
+            let biggest = sizes.iter().enumerate().max_by_key(|(team, size)| (**size, usize::MAX - *team));
+            let smallest = sizes.iter().enumerate().min_by_key(|(team, size)| (**size, *team));
+            if let (Some((biggest, big_size)), Some((smallest, small_size))) = (biggest, smallest)
+            {
+                if big_size - small_size > 1
+                {
+                    let entry = get_game_entries(db, &game.id).into_iter()
+                        .filter(|entry| entry.team == Some(biggest as u8))
+                        .max_by_key(|entry| (entry.joined_at, entry.player_id));
+                    if let Some(mut entry) = entry
+                    {
+                        entry.team = Some(smallest as u8);
+                        db.player_game_table.insert(entry.player_id, entry);
+                        continue;
+                    }
+                }
+            }
+
+            break;
+        }
+    }
+
+    fn set_game_host(db : &database::DB, mut player_game : entity::PlayerGame)
+    {
+        let game_id = player_game.game_id;
+        let player_id = player_game.player_id;
+
+        player_game.player_type = PlayerType::Host;
+        db.player_game_table.insert(player_id, player_game);
+        if let Some(player) = db.player_table.get(&player_id)
+        {
+            post_system_msg(db, &game_id, format!("{} is the new host", player.name));
+            println!("Player {} is the new host of game {}", player.name, game_id);
+        }
+    }
+
+    // Checks a message sent by a player. Returns it with filtered words masked
+    fn moderate_chat_msg(limiter : &RateLimiter, player_id : &uuid::Uuid, msg : &str, chat_filter : &Option<Regex>) -> Result<String, Error>
+    {
+        let msg = msg.trim();
+        if msg.is_empty()
+        {
+            return Err(Error::new(ErrorCode::EmptyChatMsg, "Message was empty"));
+        }
+        if msg.chars().count() > MAX_CHAT_MSG_LEN
+        {
+            let err = Error::new(ErrorCode::ChatMsgTooLong, format!("Messages can have up to {} characters", MAX_CHAT_MSG_LEN));
+            return Err(err.with_details(serde_json::json!({"max_len" : MAX_CHAT_MSG_LEN})));
+        }
+
+        // Shared by every chat, so players can't get around it by switching channels
+        if let Err(limited) = limiter.check_player("chat_msg", *player_id)
+        {
+            return Err(get_rate_limited_error(limited.retry_after));
+        }
+
+        let msg = match chat_filter {
+            Some(filter) => filter.replace_all(msg, |caps : &regex::Captures| "*".repeat(caps[0].chars().count())).to_string(),
+            None => msg.to_string(),
+        };
+        Ok(msg)
+    }
+
+    // Global channel if there's no target. Otherwise the direct messages between the player and the target.
+    // Channels which don't exist yet are returned empty, and only stored once a message is sent to them
+    fn get_chat_channel(db : &database::DB, player_id : &uuid::Uuid, target_id : Option<uuid::Uuid>) -> Result<entity::ChatChannel, Error>
+    {
+        let target_id = match target_id
+        {
+            Some(public_id) => {
+                let target_id = get_player_id(db, &public_id)
+                    .map_err(|_| Error::new(ErrorCode::InvalidTarget, format!("Cannot send direct messages to {}", public_id)))?;
+                if target_id == *player_id
+                {
+                    return Err(Error::new(ErrorCode::InvalidTarget, format!("Cannot send direct messages to {}", public_id)));
+                }
+                Some(target_id)
+            },
+            None => None,
+        };
+        let channel_id = match target_id
+        {
+            Some(target_id) => entity::ChatChannel::get_direct_id(player_id, &target_id),
+            None => entity::ChatChannel::GLOBAL_ID,
+        };
+
+        if let Some(channel) = db.chat_channel_table.get(&channel_id)
+        {
+            if !channel.members.is_empty() && !channel.members.contains(player_id)
+            {
+                return Err(Error::new(ErrorCode::InvalidTarget, format!("Player {} is not in channel {}", player_id, channel_id)));
+            }
+            return Ok(channel);
+        }
+
+        // Direct channels are only stored once a message is sent
+        let channel = match target_id
+        {
+            Some(target_id) => entity::ChatChannel::new_direct(*player_id, target_id),
+            None => entity::ChatChannel::new_global(),
+        };
+        Ok(channel)
+    }
+
+    // Drops the direct message channels whose players are all gone
+    pub fn remove_stale_channels(db : &database::DB)
+    {
+        for channel in db.chat_channel_table.get_all()
+        {
+            if !channel.members.is_empty() && channel.members.iter().all(|id| db.player_table.get(id).is_none())
+            {
+                db.chat_channel_table.remove(&channel.id);
+            }
+        }
+    }
+
+    fn post_system_msg(db : &database::DB, game_id : &uuid::Uuid, msg : String)
+    {
+        if let Some(mut game) = db.game_table.get(game_id)
+        {
+            game.chat.push(None, entity::ChatMsgKind::System, msg);
+            db.game_table.insert(game.id, game);
+        }
+    }
+
+    fn leave_game_fn(db : &database::DB, player_id : uuid::Uuid)
+    {
+        if let Some(entry)  = db.player_game_table.remove(&player_id)
+        {
+            let game_id = &entry.game_id;
+            let players : Vec<entity::PlayerGame> = get_game_entries(db, game_id).into_iter()
+                .filter(|x| !matches!(x.player_type, PlayerType::Spectator)).collect();
+            if players.is_empty()
+            {
+                // Spectators alone don't keep a game alive
+                println!("Removing game {}. There were no players left", game_id);
+                close_game(db, game_id);
+                return;
+            }
+
+            if let Some(player) = db.player_table.get(&player_id)
+            {
+                post_system_msg(db, game_id, format!("{} left the game", player.name));
+            }
+
+            if matches!(entry.player_type, PlayerType::Host)
+            {
+                // The player who has been in the game for the longest time becomes the new host
+                let new_host = players.into_iter().min_by_key(|x| (x.joined_at, x.player_id)).unwrap();
+                set_game_host(db, new_host);
             }
+            cancel_auto_start(db, game_id);
 
-            let err = format!("Key was not correct for game with id {}", server_event.game_id.to_string());
-            return Ok(reply::with_status(reply::json(&err), StatusCode::FORBIDDEN));
+            notify_game_update(db, game_id);
         }
-
-        let err = format!("Could not find game with id {}", server_event.game_id.to_string());
-        Ok(reply::with_status(reply::json(&err), StatusCode::NOT_FOUND))
     }
 
-
-    // Helper Functions
-
-    fn read_map_yaml(map : &String, maps_folder : &String) -> yaml_rust::Yaml
+    // Formats the requests warp rejected, so every error has the same envelope
+    pub async fn handle_rejection(rejection : warp::Rejection) -> Result<impl warp::Reply, Infallible>
     {
-        let map_folder = get_map_folder(map, maps_folder);
-        let map_folder = Path::new(&map_folder);
-        let yml_path = map_folder.join(map.clone() + ".yml");
-        //println!("YML path is {}", yml_path.to_str().unwrap());
+        if let Some(rate_limited) = rejection.find::<ratelimit::RateLimited>()
+        {
+            let retry_after = rate_limited.retry_after.as_secs_f32().ceil() as u64;
+            let mut response = error_reply(get_rate_limited_error(rate_limited.retry_after)).into_response();
+            response.headers_mut().insert("Retry-After", warp::http::HeaderValue::from(retry_after));
+            return Ok(response);
+        }
 
-        let mut yml_file = std::fs::File::open(&yml_path).unwrap();
-        let mut data_str = String::new();
-        yml_file.read_to_string(&mut data_str).unwrap();
-        let yml = YamlLoader::load_from_str(&data_str).unwrap().remove(0);
+        let err = if rejection.is_not_found() {
+            Error::new(ErrorCode::RouteNotFound, "Route does not exist")
+        } else if let Some(err) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+            Error::new(ErrorCode::InvalidRequest, format!("Invalid body: {}", err))
+        } else if let Some(err) = rejection.find::<warp::reject::InvalidQuery>() {
+            Error::new(ErrorCode::InvalidRequest, err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::PayloadTooLarge>() {
+            Error::new(ErrorCode::PayloadTooLarge, err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::LengthRequired>() {
+            Error::new(ErrorCode::InvalidRequest, err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+            Error::new(ErrorCode::UnsupportedMediaType, err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::MethodNotAllowed>() {
+            Error::new(ErrorCode::MethodNotAllowed, err.to_string())
+        } else {
+            println!("Unhandled rejection {:?}", rejection);
+            Error::new(ErrorCode::Internal, "Internal error")
+        };
+
+        Ok(error_reply(err).into_response())
+    }
 
-        return yml;
+    fn get_rate_limited_error(retry_after : Duration) -> Error
+    {
+        let retry_after_ms = retry_after.as_millis() as u64;
+        let err = Error::new(ErrorCode::RateLimited, format!("Too many requests. Retry in {} ms", retry_after_ms));
+        err.with_details(serde_json::json!({"retry_after_ms" : retry_after_ms}))
     }
 
-    fn get_map_folder(map : &String, maps_folder : &String) -> String
+    fn get_error_status(code : ErrorCode) -> StatusCode
     {
-        let maps_folder = Path::new(&maps_folder);
-        let map_folder = maps_folder.join(&map);
-        return map_folder.as_os_str().to_str().unwrap().to_string();
+        match code
+        {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidMapName | ErrorCode::InvalidMapVersion | ErrorCode::InvalidMapMetadata | ErrorCode::InvalidGameName 
+                | ErrorCode::InvalidGameMode | ErrorCode::ModeNotSupported | ErrorCode::InvalidMaxPlayers | ErrorCode::InvalidMaxSpectators | ErrorCode::InvalidTeam 
+                | ErrorCode::InvalidTarget | ErrorCode::EmptyChatMsg => StatusCode::BAD_REQUEST,
+            ErrorCode::NotHost | ErrorCode::WrongPassword | ErrorCode::WrongServerKey | ErrorCode::NotAdmin | ErrorCode::PlayerBanned 
+                | ErrorCode::PlayerMuted | ErrorCode::TeamsLocked => StatusCode::FORBIDDEN,
+            ErrorCode::PlayerNotFound | ErrorCode::PlayerNotInGame | ErrorCode::GameNotFound | ErrorCode::MapNotFound 
+                | ErrorCode::MapPictureNotFound | ErrorCode::UploadNotFound | ErrorCode::HostNotFound | ErrorCode::NoGameAvailable | ErrorCode::RouteNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::InvalidMap | ErrorCode::InvalidPicture | ErrorCode::HashMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::GameFull | ErrorCode::TeamFull | ErrorCode::InvalidGameState | ErrorCode::UploadIncomplete 
+                | ErrorCode::MapVersionExists | ErrorCode::MapExists | ErrorCode::MapInUse => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge | ErrorCode::ChatMsgTooLong => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::RateLimited | ErrorCode::TooManyUploads => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::LaunchFailed | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 
-    fn zip_extract(file : File, folder : &String)
+    fn error_reply(err : Error) -> reply::WithStatus<reply::Json>
     {
-        let folder = Path::new(folder.as_str());
-        let mut archive = zip::ZipArchive::new(file).unwrap();
+        let status = get_error_status(err.code);
+        reply::with_status(reply::json(&err), status)
+    }
 
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i).unwrap();
-            let outpath = folder.join(file.mangled_name());
-    
-            if (&*file.name()).ends_with('/') {
-                //println!("File {} extracted to \"{}\"", i, outpath.as_path().display());
-                std::fs::create_dir_all(&outpath).unwrap();
-            } else {
-                //println!("File {} extracted to \"{}\" ({} bytes)", i, outpath.as_path().display(), file.size());
-                if let Some(p) = outpath.parent() {
-                    if !p.exists() {
-                        std::fs::create_dir_all(&p).unwrap();
-                    }
-                }
-                let mut outfile = std::fs::File::create(&outpath).unwrap();
-                std::io::copy(&mut file, &mut outfile).unwrap();
-            }
-    
-            // Get and Set permissions
-            #[cfg(unix)]
+    // Called periodically. Drives the time based transitions of a game
+    pub fn update_game_lifecycle(db : &database::DB, game_id : uuid::Uuid, map_catalog : &MapCatalog, server_tickrate : f32, mm_address : &str, mm_port : u16)
+    {
+        if let Some(game) = db.game_table.get(&game_id)
+        {
+            match game.state
             {
-                use std::os::unix::fs::PermissionsExt;
-    
-                if let Some(mode) = file.unix_mode() {
-                    std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(mode)).unwrap();
-                }
+                GameState::Starting{deadline} if get_unix_time_ms() >= deadline => {
+                    // Errors are already reported to the players
+                    let _ = start_game_fn(db, game_id, map_catalog, server_tickrate, mm_address, mm_port);
+                },
+                GameState::PostGame if game.get_state_elapsed() >= POST_GAME_DURATION.as_millis() as u64 => {
+                    return_to_lobby(db, &game_id);
+                },
+                GameState::Failed if game.get_state_elapsed() >= FAILED_DURATION.as_millis() as u64 => {
+                    return_to_lobby(db, &game_id);
+                },
+                _ => {}
             }
         }
     }
 
-    fn _zip_dir<T>(it: &mut dyn Iterator<Item=DirEntry>, prefix: &str, writer: T, method: zip::CompressionMethod)
-              -> zip::result::ZipResult<()> where T: Write+Seek
+    fn return_to_lobby(db : &database::DB, game_id : &uuid::Uuid)
     {
-        let mut zip = zip::ZipWriter::new(writer);
-        let options = FileOptions::default().compression_method(method).unix_permissions(0o755);
-        
-        for entry in it {
-            let path = entry.path();
-            let name = path.strip_prefix(Path::new(prefix)).unwrap();
-
-            if path.is_file() {
-                zip.start_file(name.to_str().unwrap(), options)?;
-                let mut f = File::open(path)?;
-                
-                let mut buffer = Vec::new();
-                f.read_to_end(&mut buffer)?;
-                zip.write_all(&*buffer)?;
-
-                buffer.clear();
-            } else if name.as_os_str().len() != 0 {
+        if let Some(mut game) = db.game_table.get(game_id)
+        {
+            if game.set_state(GameState::Lobby).is_ok()
+            {
+                game.address = None;
+                game.port = None;
+                db.game_table.insert(game.id, game);
 
-                zip.add_directory(name.to_str().unwrap(), options)?;
+                update_auto_start(db, game_id);
+                notify_game_update(db, game_id);
             }
         }
-
-        zip.finish()?;
-        Result::Ok(())
-    }
-
-    fn is_map_name_valid(map_name : &String) -> bool
-    {
-        let reg = regex::Regex::new(r"[A-z0-9]*").unwrap();
-        return reg.is_match(map_name);
     }
 
-    #[derive(Debug)]
-    enum JoinGameError
+    // Removes the game and everyone still in it
+    pub fn close_game(db : &database::DB, game_id : &uuid::Uuid)
     {
-        GameFull,
-        PlayerNotFound,
-        GameNotFound,
+        if set_game_state(db, game_id, GameState::Closed).is_ok()
+        {
+            get_game_entries(db, game_id).iter().for_each(|x| { db.player_game_table.remove(&x.player_id); });
+            notify_game_update(db, game_id);
+            db.game_table.remove(game_id);
+            db.game_sem_table.remove(game_id);
+        }
     }
 
-    fn join_game_as(db : &database::DB, player_id : uuid::Uuid, game_id : uuid::Uuid, host : bool) -> Result<entity::PlayerGame, JoinGameError>
+    // Starts the countdown once every player is ready, or cancels it otherwise
+    fn update_auto_start(db : &database::DB, game_id : &uuid::Uuid)
     {
-        if let Ok(full) = is_game_full(db, &game_id)
+        if let Some(game) = db.game_table.get(game_id)
         {
-            if !full {
-                // Find player
-                if let Some(_player) = db.player_table.get(&player_id)
-                {
-                    // Insert new entry
-                    let player_game = if host {entity::PlayerGame::new_host(player_id, game_id)} else {entity::PlayerGame::new_player(player_id, game_id)};
-                    db.player_game_table.insert(player_id, player_game.clone());
+            let entries = get_game_entries(db, game_id);
+            let players = entries.iter().filter(|x| !matches!(x.player_type, PlayerType::Spectator)).count();
+            let all_ready = entries.iter().all(|x| !matches!(x.player_type, PlayerType::Player(false)));
+            let can_start = game.auto_start && all_ready && players >= modes::get_min_players(&game.mode) as usize;
 
-                    return Ok(player_game);
-                }
-                return Err(JoinGameError::PlayerNotFound);
+            match game.state
+            {
+                GameState::Lobby if can_start => {
+                    let deadline = get_unix_time_ms() + AUTO_START_COUNTDOWN.as_millis() as u64;
+                    let _ = set_game_state(db, game_id, GameState::Starting{deadline});
+                    post_system_msg(db, game_id, format!("Game starts in {} seconds", AUTO_START_COUNTDOWN.as_secs()));
+                },
+                GameState::Starting{..} if !can_start => cancel_auto_start(db, game_id),
+                _ => {}
             }
-            return Err(JoinGameError::GameFull);
         }
-        Err(JoinGameError::GameNotFound)
     }
-    enum LaunchServerError
+
+    // Players who leave or are removed change the roster the countdown started with, so it's cancelled even if the
+    // rest are still ready. It starts again once a player toggles ready
+    fn cancel_auto_start(db : &database::DB, game_id : &uuid::Uuid)
     {
-        ServerCrashed,
-        CouldNotlaunch,
-        GameNotFound
+        let starting = db.game_table.get(game_id).map(|game| matches!(game.state, GameState::Starting{..})).unwrap_or(false);
+        if starting && set_game_state(db, game_id, GameState::Lobby).is_ok()
+        {
+            post_system_msg(db, game_id, "Countdown was cancelled".to_string());
+        }
     }
 
-    fn leave_game_fn(db : &database::DB, player_id : uuid::Uuid)
+    // Launches the game's server and moves every player to it
+    fn start_game_fn(db : &database::DB, game_id : uuid::Uuid, map_catalog : &MapCatalog, server_tickrate : f32, mm_address : &str, mm_port : u16) 
+        -> Result<(), LaunchServerError>
     {
-        if let Some(entry)  = db.player_game_table.remove(&player_id)
+        set_game_state(db, &game_id, GameState::Launching).map_err(|_| LaunchServerError::InvalidState)?;
+
+        let res = launch_game(db, game_id, map_catalog, server_tickrate, mm_address, mm_port);
+        if let Some(mut game) = db.game_table.get(&game_id)
         {
-            let game_id = &entry.game_id;
-            let game_players = get_game_players(&db, game_id);
-            if game_players.is_empty()
-            {
-                db.game_table.remove(game_id);
-                println!("Removing game {}. There were no players left", game_id);
-            }
-            else if matches!(entry.player_type, PlayerType::Host)
+            match &res
             {
-                let player = game_players.first().unwrap();
-                let mut new_host = db.player_game_table.get(&player.id).unwrap();
-                new_host.player_type = PlayerType::Host;
-                db.player_game_table.insert(player.id, new_host);
-                println!("Player {} is the new host of game {}", player.name, game_id);
+                Ok((address, port)) => {
+                    game.port = Some(*port);
+                    game.address = Some(address.clone());
+                    let _ = game.set_state(GameState::Running);
+                    game.chat.push(None, entity::ChatMsgKind::System, "Game has started".to_string());
+                    db.game_table.insert(game.id, game);
+                },
+                Err(_) => {
+                    let _ = game.set_state(GameState::Failed);
+                    db.game_table.insert(game.id, game);
+                    post_system_msg(db, &game_id, "Could not launch game".to_string());
+                    notify_game_update(db, &game_id);
+                }
             }
-
-            notify_game_update(&db, game_id);
         }
+        res?;
+
+        get_game_players(db, &game_id).into_iter().for_each(|x| set_player_ready(db, &x.id, false));
+        notify_game_update(db, &game_id);
+
+        Ok(())
     }
 
-    fn launch_game(db : &database::DB, game_id : uuid::Uuid, exec_path : String, maps_folder : String, server_tickrate : f32, mm_port : u16) 
-        -> Result<(& str, u16), LaunchServerError>
+    // Hosts may run the server on another machine, where the map file doesn't exist. Their scripts can download the map from
+    // the given URL instead, and report events to the matchmaker at the given address
+    fn launch_game(db : &database::DB, game_id : uuid::Uuid, map_catalog : &MapCatalog, server_tickrate : f32, mm_address : &str, mm_port : u16) 
+        -> Result<(String, u16), LaunchServerError>
     {
         if let Some(game) = db.game_table.get(&game_id)
         {   
-            let game_info = get_game_info(db, &game_id).unwrap();
+            let game_info = get_game_info(db, &game_id).map_err(|_| LaunchServerError::GameNotFound)?;
+            let host = db.host_table.get(&game.host_id).ok_or(LaunchServerError::HostNotFound)?;
             
             let listen_address = "0.0.0.0";
-            let port = get_free_port(db);
+            let port = get_free_port(db, &host.id);
             
-            let program = exec_path;
-            let maps_folder = Path::new(&maps_folder);
-            let map_folder = maps_folder.join(&game_info.map);
-            let map_path = map_folder.join(game_info.map + ".bbm");
+            let program = host.server_path;
+            // Games are launched with the version they were created with, even if the map was updated since then
+            let map_path = map_catalog.get_map_file_path(&game_info.map, &game.map_version);
+            let map_url = format!("http://{}:{}/maps/{}/{}.zip", mm_address, mm_port, game_info.map, game.map_version);
             let gamemode = game_info.mode;
 
             let res = Command::new(program)
                 .arg("-a").arg(listen_address)
                 .arg("-p").arg(port.to_string())
                 .arg("-m").arg(map_path)
+                .arg("-mu").arg(map_url)
                 .arg("-mp").arg(game.max_players.to_string())
                 .arg("-sp").arg(game_info.players.to_string())
                 .arg("-gm").arg(gamemode)
                 .arg("-t").arg(server_tickrate.to_string())
                 
+                .arg("-mma").arg(mm_address)
                 .arg("-mmp").arg(mm_port.to_string())
                 .arg("-mmid").arg(game_id.to_string())
                 .arg("-mmk").arg(game.key.to_string())
+                .args(get_teams_arg(db, &game))
+                .args(get_spectators_arg(db, &game))
                 .spawn();
 
             if let Err(error) = res
@@ -667,29 +2308,64 @@ pub mod handlers
                 return Err(LaunchServerError::CouldNotlaunch);
             }
 
-            set_game_state(&db, &game_id, GameState::InGame);
-
-            return Ok((&listen_address, port));
+            map_catalog.add_play(&game_info.map);
+            return Ok((host.address, port));
         }
 
         return Err(LaunchServerError::GameNotFound);
     }
 
-    fn set_game_state(db : &database::DB, game_id : &uuid::Uuid, state : GameState)
+    // Team of each player, formatted as -teams <public_id>=<team>,<public_id>=<team>...
+    // Session ids must not end up in the command line, where other users of the machine can read them
+    fn get_teams_arg(db : &database::DB, game : &entity::Game) -> Vec<String>
     {
-        if let Some(mut game) = db.game_table.get(&game_id)
+        let teams : Vec<String> = get_game_entries(db, &game.id).into_iter()
+            .filter_map(|entry| {
+                let player = db.player_table.get(&entry.player_id)?;
+                entry.team.map(|team| format!("{}={}", player.public_id, team))
+            })
+            .collect();
+        if teams.is_empty()
+        {
+            return Vec::new();
+        }
+
+        vec!["-teams".to_string(), teams.join(",")]
+    }
+
+    // Public ids of the spectators, formatted as -spectators <public_id>,<public_id>...
+    fn get_spectators_arg(db : &database::DB, game : &entity::Game) -> Vec<String>
+    {
+        let spectators : Vec<String> = get_game_entries(db, &game.id).into_iter()
+            .filter(|entry| matches!(entry.player_type, PlayerType::Spectator))
+            .filter_map(|entry| db.player_table.get(&entry.player_id))
+            .map(|player| player.public_id.to_string())
+            .collect();
+        if spectators.is_empty()
+        {
+            return Vec::new();
+        }
+
+        vec!["-spectators".to_string(), spectators.join(",")]
+    }
+
+    fn set_game_state(db : &database::DB, game_id : &uuid::Uuid, state : GameState) -> Result<(), entity::InvalidTransition>
+    {
+        if let Some(mut game) = db.game_table.get(game_id)
         {
-            game.state = state;
-            game.last_update = std::time::SystemTime::now();
+            game.set_state(state)?;
             db.game_table.insert(game.id, game);
         }
+
+        Ok(())
     }
 
-    fn get_free_port(db : &database::DB) -> u16
+    // Ports are only shared by the games of the same host
+    fn get_free_port(db : &database::DB, host_id : &uuid::Uuid) -> u16
     {
         let mut port = rand::thread_rng().gen_range(8000..8400);
 
-        while is_port_in_use(db, port)
+        while is_port_in_use(db, host_id, port)
         {
             port = rand::thread_rng().gen_range(8000..8400);
         }
@@ -697,9 +2373,9 @@ pub mod handlers
         return port;
     }
 
-    fn is_port_in_use(db : &database::DB, port : u16) -> bool
+    fn is_port_in_use(db : &database::DB, host_id : &uuid::Uuid, port : u16) -> bool
     {
-        for entry in db.game_table.get_all().into_iter()
+        for entry in db.game_table.get_all().into_iter().filter(|x| x.host_id == *host_id)
         {
             if let Some(entry_port) = entry.port
             {
@@ -716,13 +2392,13 @@ pub mod handlers
     fn notify_game_update(db : &database::DB, game_id : &uuid::Uuid) -> bool
     {
         let mut notified = false;
-        if let Some(game_sem) = db.game_sem_table.get(&game_id)
+        if let Some(game_sem) = db.game_sem_table.get(game_id)
         {
             game_sem.sem.notify_all();
             notified = true;
             
             // Update last time this game had changes
-            if let Some(mut game) = db.game_table.get(&game_id)
+            if let Some(mut game) = db.game_table.get(game_id)
             {
                 game.last_update = std::time::SystemTime::now();
                 db.game_table.insert(game.id, game);
@@ -738,21 +2414,43 @@ pub mod handlers
 
     fn get_game_details(db : &database::DB, game_id : &uuid::Uuid) -> Result<payload::response::GameDetails, QueryError>
     {
-        let game_players = get_game_players_info(&db, &game_id);
+        let game_players = get_game_players_info(db, game_id);
 
-        if let Ok(game_info) = get_game_info(db, &game_id){
-            return Ok(payload::response::GameDetails{game_info, players :  game_players})
+        if let (Ok(game_info), Some(game)) = (get_game_info(db, game_id), db.game_table.get(game_id)){
+            return Ok(payload::response::GameDetails{game_info, players :  game_players, state_history : game.state_history})
         }
 
         Err(QueryError::EntityNotFound)
     }
 
+    fn game_details_reply(db : &database::DB, game_id : &uuid::Uuid) -> reply::WithStatus<reply::Json>
+    {
+        match get_game_details(db, game_id)
+        {
+            Ok(game_details) => reply::with_status(reply::json(&game_details), StatusCode::OK),
+            Err(_) => error_reply(Error::new(ErrorCode::GameNotFound, format!("Could not find game with id {}", game_id))),
+        }
+    }
+
     fn is_game_full(db : &database::DB, game_id : &uuid::Uuid) -> Result<bool, QueryError>
     {
-        let game_players = get_game_players_info(&db, &game_id);
+        let game_players = get_game_players_info(db, game_id);
+        if let Some(game) = db.game_table.get(game_id)
+        {
+            let players = game_players.iter().filter(|x| !x.spectator).count();
+            return Ok(players as u8 >= game.max_players);
+        }
+
+        return Err(QueryError::EntityNotFound);
+    }
+
+    fn is_spectators_full(db : &database::DB, game_id : &uuid::Uuid) -> Result<bool, QueryError>
+    {
+        let game_players = get_game_players_info(db, game_id);
         if let Some(game) = db.game_table.get(game_id)
         {
-            return Ok(game_players.len() as u8 >= game.max_players);
+            let spectators = game_players.iter().filter(|x| x.spectator).count();
+            return Ok(spectators as u8 >= game.max_spectators);
         }
 
         return Err(QueryError::EntityNotFound);
@@ -760,7 +2458,7 @@ pub mod handlers
 
     fn _is_game_empty(db : &database::DB, game_id : &uuid::Uuid) -> bool
     {
-        let game_players = get_game_players_info(&db, &game_id);
+        let game_players = get_game_players_info(db, game_id);
         return game_players.is_empty();
     }
 
@@ -774,19 +2472,30 @@ pub mod handlers
     {
         if let Some(game) = db.game_table.get(game_id)
         {
-            let players = get_game_players_info(&db, &game_id);
-            let player_amount = players.len() as u8;
-            let ping = 56;
+            let players = get_game_players_info(db, game_id);
+            let spectator_amount = players.iter().filter(|x| x.spectator).count() as u8;
+            let player_amount = players.len() as u8 - spectator_amount;
+            let ping = get_host_ping(db, &game.host_id, None);
+            let region = db.host_table.get(&game.host_id).map(|host| host.region).unwrap_or_default();
+            let teams = modes::get_team_count(&game.mode);
             return Ok(payload::response::GameInfo{
                 id : game.id,
                 name : game.name, 
                 map : game.map, 
                 map_version : game.map_version,
+                map_zip_sha256 : game.map_zip_sha256,
+                map_picture_sha256 : game.map_picture_sha256,
                 mode : game.mode, 
                 max_players : game.max_players, 
                 players : player_amount, 
+                max_spectators : game.max_spectators,
+                spectators : spectator_amount,
                 ping,
+                region,
                 chat : game.chat.to_vec(),
+                teams,
+                teams_locked : game.teams_locked,
+                auto_start : game.auto_start,
 
                 address : game.address,
                 port : game.port,
@@ -797,19 +2506,68 @@ pub mod handlers
         Err(QueryError::EntityNotFound)
     }
 
+    // Picks the least loaded host in the given region, or in the player's lowest latency region if none is given
+    fn select_host(db : &database::DB, player_id : &uuid::Uuid, region : Option<&String>) -> Option<entity::GameHost>
+    {
+        let region = region.cloned().or_else(|| get_player_region(db, player_id));
+        let games = db.game_table.get_all();
+
+        db.host_table.get_all().into_iter()
+            .filter(|host| region.as_ref().map(|region| host.region == *region).unwrap_or(true))
+            .min_by_key(|host| games.iter().filter(|game| game.host_id == host.id).count())
+    }
+
+    // Region of the host with the lowest ping reported by the player
+    fn get_player_region(db : &database::DB, player_id : &uuid::Uuid) -> Option<String>
+    {
+        let player_ping = db.player_ping_table.get(player_id)?;
+        let (host_id, _ping) = player_ping.pings.iter().min_by_key(|(_host_id, ping)| **ping)?;
+        let host = db.host_table.get(host_id)?;
+
+        Some(host.region)
+    }
+
+    // Returns the player's reported RTT to the host. If the player has not reported it, or no player is given,
+    // the average reported by every player is used instead. 0 means unknown.
+    fn get_host_ping(db : &database::DB, host_id : &uuid::Uuid, player_id : Option<&uuid::Uuid>) -> u16
+    {
+        if let Some(player_ping) = player_id.and_then(|id| db.player_ping_table.get(id))
+        {
+            if let Some(ping) = player_ping.pings.get(host_id)
+            {
+                return *ping;
+            }
+        }
+
+        let pings : Vec<u32> = db.player_ping_table.get_all().into_iter()
+            .filter_map(|player_ping| player_ping.pings.get(host_id).map(|ping| *ping as u32))
+            .collect();
+        if pings.is_empty()
+        {
+            return 0;
+        }
+
+        return (pings.iter().sum::<u32>() / pings.len() as u32) as u16;
+    }
+
     fn set_player_ready(db : &database::DB, player_id : &uuid::Uuid, ready : bool)
     {
-        if let Some(mut player_game)  = db.player_game_table.get(&player_id)
+        if let Some(mut player_game)  = db.player_game_table.get(player_id)
         {
             if let entity::PlayerType::Player(_is_ready) = player_game.player_type
             {
                 player_game.player_type = entity::PlayerType::Player(ready);
-                db.player_game_table.insert(player_id.clone(), player_game.clone());
-                notify_game_update(&db, &player_game.game_id);
+                db.player_game_table.insert(*player_id, player_game.clone());
+                notify_game_update(db, &player_game.game_id);
             }
         }
     }
 
+    fn get_game_entries(db : &database::DB, game_id : &uuid::Uuid) -> Vec<entity::PlayerGame>
+    {
+        db.player_game_table.get_all().into_iter().filter(|entry| entry.game_id == *game_id).collect()
+    }
+
     fn get_game_players(db : &database::DB, game_id : &uuid::Uuid) -> Vec<entity::Player>
     {
         let mut game_players = Vec::new();
@@ -819,32 +2577,394 @@ pub mod handlers
             
             if *game_id == entry_game_id
             {
-                let player = db.player_table.get(&entry.player_id).expect("Could not find player in playertable");
-                game_players.push(player);
+                if let Some(player) = db.player_table.get(&entry.player_id)
+                {
+                    game_players.push(player);
+                }
+            }
+        }
+
+        game_players
+    }
+
+    fn get_game_players_info(db : &database::DB, game_id : &uuid::Uuid) -> Vec<payload::response::PlayerInfo>
+    {
+        let mut game_players = Vec::new();
+        let muted = db.game_table.get(game_id).map(|game| game.muted).unwrap_or_default();
+        for entry in db.player_game_table.get_all().into_iter()
+        {
+            let entry_game_id = entry.game_id;
+            
+            if *game_id == entry_game_id
+            {
+                let player = match db.player_table.get(&entry.player_id)
+                {
+                    Some(player) => player,
+                    None => continue,
+                };
+                let ready = match entry.player_type {entity::PlayerType::Player(ready) => ready, _ => false};
+                let host = matches!(entry.player_type, entity::PlayerType::Host);
+                let spectator = matches!(entry.player_type, entity::PlayerType::Spectator);
+                let muted = muted.contains(&entry.player_id);
+                let player_info = payload::response::PlayerInfo{id : player.public_id, name : player.name, ready, host, spectator, team : entry.team, muted};
+                game_players.push(player_info);
+            }
+        }
+
+        game_players
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        fn headers(entries : &[(header::HeaderName, &str)]) -> HeaderMap
+        {
+            let mut headers = HeaderMap::new();
+            for (name, value) in entries
+            {
+                headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+            }
+            headers
+        }
+
+        #[test]
+        fn parses_ranges()
+        {
+            assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+            assert_eq!(parse_range("bytes=100-", 1000), Some((100, 999)));
+            assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+            assert_eq!(parse_range("bytes= 5 - 9 ", 1000), Some((5, 9)));
+            assert_eq!(parse_range("bytes=0-0", 1), Some((0, 0)));
+        }
+
+        #[test]
+        fn clamps_ranges_to_file()
+        {
+            assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 999)));
+            assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+        }
+
+        #[test]
+        fn rejects_unsatisfiable_ranges()
+        {
+            // Every None is answered with 416 Range Not Satisfiable
+            assert_eq!(parse_range("bytes=1000-", 1000), None);
+            assert_eq!(parse_range("bytes=1000-1999", 1000), None);
+            assert_eq!(parse_range("bytes=50-10", 1000), None);
+            assert_eq!(parse_range("bytes=0-", 0), None);
+            assert_eq!(parse_range("bytes=-10", 0), None);
+        }
+
+        #[test]
+        fn rejects_malformed_ranges()
+        {
+            assert_eq!(parse_range("0-99", 1000), None);
+            assert_eq!(parse_range("items=0-99", 1000), None);
+            assert_eq!(parse_range("bytes=", 1000), None);
+            assert_eq!(parse_range("bytes=10", 1000), None);
+            assert_eq!(parse_range("bytes=a-b", 1000), None);
+            assert_eq!(parse_range("bytes=--5", 1000), None);
+            assert_eq!(parse_range("bytes=-1-5", 1000), None);
+        }
+
+        #[test]
+        fn applies_if_range()
+        {
+            let etag = "\"abc\"";
+            let range = (header::RANGE, "bytes=0-99");
+            assert_eq!(get_range(&headers(std::slice::from_ref(&range)), etag), Some("bytes=0-99"));
+            assert_eq!(get_range(&headers(&[range.clone(), (header::IF_RANGE, etag)]), etag), Some("bytes=0-99"));
+            assert_eq!(get_range(&headers(&[range, (header::IF_RANGE, "\"old\"")]), etag), None);
+            assert_eq!(get_range(&headers(&[(header::IF_RANGE, etag)]), etag), None);
+        }
+
+        #[test]
+        fn ignores_multiple_ranges()
+        {
+            assert_eq!(get_range(&headers(&[(header::RANGE, "bytes=0-9,20-29")]), "\"abc\""), None);
+        }
+
+        #[test]
+        fn matches_if_none_match()
+        {
+            let etag = "\"abc\"";
+            assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, etag)]), etag));
+            assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"old\", \"abc\"")]), etag));
+            assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "*")]), etag));
+            assert!(!is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"old\"")]), etag));
+            assert!(!is_not_modified(&HeaderMap::new(), etag));
+        }
+
+        // Lobby with auto start on, hosted by the first player. The others join without being ready
+        fn create_lobby(db : &database::DB, players : usize) -> (uuid::Uuid, Vec<uuid::Uuid>)
+        {
+            let host = entity::GameHost::new("local".to_string(), "eu".to_string(), "127.0.0.1".to_string(), 0, "true".to_string());
+            db.host_table.insert(host.id, host.clone());
+
+            let mut game = entity::Game::new("Test".to_string(), "arena".to_string(), "1".to_string(), "Free for All".to_string(), 8, 4, host.id);
+            game.auto_start = true;
+            db.game_table.insert(game.id, game.clone());
+            db.game_sem_table.insert(game.id, entity::GameSem::new(game.id));
+
+            let mut ids = Vec::new();
+            for i in 0..players
+            {
+                let player = entity::Player::new(format!("Player{}", i));
+                let entry = if i == 0 { entity::PlayerGame::new_host(player.id, game.id) } else { entity::PlayerGame::new_player(player.id, game.id) };
+                db.player_table.insert(player.id, player.clone());
+                db.player_game_table.insert(player.id, entry);
+                ids.push(player.id);
+            }
+
+            (game.id, ids)
+        }
+
+        async fn toggle(db : &database::DB, player_id : uuid::Uuid)
+        {
+            let _ = toggle_ready(payload::request::ToggleReady{player_id}, db.clone()).await;
+        }
+
+        fn get_state(db : &database::DB, game_id : &uuid::Uuid) -> GameState
+        {
+            db.game_table.get(game_id).unwrap().state
+        }
+
+        fn get_last_msg(db : &database::DB, game_id : &uuid::Uuid) -> String
+        {
+            db.game_table.get(game_id).unwrap().chat.to_vec().last().unwrap().text.clone()
+        }
+
+        #[tokio::test]
+        async fn starts_countdown_when_everyone_is_ready()
+        {
+            let db = database::DB::new();
+            let (game_id, ids) = create_lobby(&db, 3);
+
+            toggle(&db, ids[1]).await;
+            assert_eq!(get_state(&db, &game_id), GameState::Lobby);
+
+            toggle(&db, ids[2]).await;
+            assert!(matches!(get_state(&db, &game_id), GameState::Starting{..}));
+        }
+
+        #[test]
+        fn waits_for_min_players()
+        {
+            let db = database::DB::new();
+            let (game_id, _) = create_lobby(&db, 1);
+
+            update_auto_start(&db, &game_id);
+            assert_eq!(get_state(&db, &game_id), GameState::Lobby);
+        }
+
+        #[tokio::test]
+        async fn cancels_countdown_on_unready()
+        {
+            let db = database::DB::new();
+            let (game_id, ids) = create_lobby(&db, 2);
+            toggle(&db, ids[1]).await;
+            assert!(matches!(get_state(&db, &game_id), GameState::Starting{..}));
+
+            toggle(&db, ids[1]).await;
+            assert_eq!(get_state(&db, &game_id), GameState::Lobby);
+            assert_eq!(get_last_msg(&db, &game_id), "Countdown was cancelled");
+        }
+
+        #[tokio::test]
+        async fn cancels_countdown_on_leave()
+        {
+            let db = database::DB::new();
+            let (game_id, ids) = create_lobby(&db, 4);
+            for id in &ids[1..]
+            {
+                toggle(&db, *id).await;
             }
+            assert!(matches!(get_state(&db, &game_id), GameState::Starting{..}));
+
+            // The rest are still ready and enough to start, but the roster changed
+            let _ = leave_game(payload::request::LeaveGame{player_id : ids[3]}, db.clone()).await;
+            assert_eq!(get_state(&db, &game_id), GameState::Lobby);
+            assert!(db.game_table.get(&game_id).unwrap().chat.to_vec().iter().any(|msg| msg.text == "Countdown was cancelled"));
+
+            // Kicks cancel it too
+            toggle(&db, ids[2]).await;
+            toggle(&db, ids[2]).await;
+            assert!(matches!(get_state(&db, &game_id), GameState::Starting{..}));
+            remove_player_as_host(&db, ids[0], ids[2], false).unwrap();
+            assert_eq!(get_state(&db, &game_id), GameState::Lobby);
+        }
+
+        #[tokio::test]
+        async fn servers_only_get_public_ids()
+        {
+            let db = database::DB::new();
+            let (game_id, ids) = create_lobby(&db, 3);
+            for (team, id) in ids.iter().enumerate()
+            {
+                let mut entry = db.player_game_table.get(id).unwrap();
+                entry.team = Some(team as u8);
+                db.player_game_table.insert(*id, entry);
+            }
+
+            let game = db.game_table.get(&game_id).unwrap();
+            let teams_arg = get_teams_arg(&db, &game);
+            assert_eq!(teams_arg[0], "-teams");
+            let public_ids : Vec<uuid::Uuid> = ids.iter().map(|id| db.player_table.get(id).unwrap().public_id).collect();
+            for (id, public_id) in ids.iter().zip(public_ids.iter())
+            {
+                assert!(!teams_arg[1].contains(&id.to_string()));
+                assert!(teams_arg[1].contains(&format!("{}=", public_id)));
+            }
+
+            let event = payload::request::ServerEvent::PlayerLeft{player_id : public_ids[2]};
+            let _ = notify_server_event(payload::request::NotifyServerEvent{server_key : game.key, game_id, event}, db.clone()).await;
+            assert!(db.player_game_table.get(&ids[2]).is_none());
+        }
+
+        #[test]
+        fn spectators_arg_has_public_ids()
+        {
+            let db = database::DB::new();
+            let (game_id, _) = create_lobby(&db, 2);
+            let spectator = entity::Player::new("Spectator".to_string());
+            db.player_table.insert(spectator.id, spectator.clone());
+            db.player_game_table.insert(spectator.id, entity::PlayerGame::new_spectator(spectator.id, game_id));
+
+            let game = db.game_table.get(&game_id).unwrap();
+            assert_eq!(get_spectators_arg(&db, &game), vec!["-spectators".to_string(), spectator.public_id.to_string()]);
+        }
+
+        fn create_map_info(min_players : Option<u8>, max_players : Option<u8>) -> payload::response::MapInfo
+        {
+            let metadata = MapMetadata{supported_gamemodes : vec!["Free for All".to_string()], min_players, max_players, ..Default::default()};
+            payload::response::MapInfo{map_name : "arena".to_string(), map_version : "1".to_string(), metadata, versions : vec!["1".to_string()], 
+                zip_sha256 : None, picture_sha256 : None, thumbnail_sizes : Vec::new(), uploaded_at : 0, times_played : 0}
+        }
+
+        fn check_settings(map_info : &payload::response::MapInfo, max_players : u8, max_spectators : u8) -> Result<(), ErrorCode>
+        {
+            check_game_settings(map_info, "Test", "Free for All", max_players, max_spectators).map(|_| ()).map_err(|err| err.code)
+        }
+
+        #[test]
+        fn checks_game_settings()
+        {
+            let map_info = create_map_info(Some(4), Some(8));
+            assert_eq!(check_settings(&map_info, 4, 0), Ok(()));
+            assert_eq!(check_settings(&map_info, 8, MAX_SPECTATORS), Ok(()));
+            assert_eq!(check_settings(&map_info, 3, 0), Err(ErrorCode::InvalidMaxPlayers));
+            assert_eq!(check_settings(&map_info, 9, 0), Err(ErrorCode::InvalidMaxPlayers));
+            assert_eq!(check_settings(&map_info, 8, MAX_SPECTATORS + 1), Err(ErrorCode::InvalidMaxSpectators));
+            assert_eq!(check_game_settings(&map_info, " ", "Free for All", 8, 0).unwrap_err().code, ErrorCode::InvalidGameName);
+            assert_eq!(check_game_settings(&map_info, "Test", "Racing", 8, 0).unwrap_err().code, ErrorCode::InvalidGameMode);
+            assert_eq!(check_game_settings(&map_info, "Test", "Domination", 8, 0).unwrap_err().code, ErrorCode::ModeNotSupported);
+        }
+
+        #[test]
+        fn rejects_maps_too_big_for_mode()
+        {
+            // Free for All allows up to 16 players
+            let map_info = create_map_info(Some(20), None);
+            assert_eq!(check_settings(&map_info, 16, 0), Err(ErrorCode::ModeNotSupported));
+            assert_eq!(check_settings(&map_info, 20, 0), Err(ErrorCode::ModeNotSupported));
+        }
+
+        #[test]
+        fn only_spectators_join_running_games()
+        {
+            let db = database::DB::new();
+            let (game_id, _) = create_lobby(&db, 2);
+            let mut game = db.game_table.get(&game_id).unwrap();
+            game.state = GameState::Running;
+            db.game_table.insert(game_id, game);
+
+            let player = entity::Player::new("Late".to_string());
+            db.player_table.insert(player.id, player.clone());
+            assert!(matches!(join_game_as(&db, player.id, game_id, PlayerType::Player(false)), Err(JoinGameError::NotJoinable)));
+            assert!(join_game_as(&db, player.id, game_id, PlayerType::Spectator).is_ok());
+        }
+
+        #[tokio::test]
+        async fn hosts_only_manage_their_game()
+        {
+            let db = database::DB::new();
+            let (game_a, ids_a) = create_lobby(&db, 2);
+            let (game_b, _) = create_lobby(&db, 2);
+            let maps_folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let map_catalog = MapCatalog::load(maps_folder.to_str().unwrap(), 1);
+
+            let eg_req = payload::request::EditGame{player_id : ids_a[0], game_id : game_b, name : "Taken".to_string(), map : "arena".to_string(), 
+                mode : "Free for All".to_string(), auto_start : None};
+            let res = edit_game(eg_req, db.clone(), map_catalog.clone()).await.unwrap().into_response();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(db.game_table.get(&game_b).unwrap().name, "Test");
+
+            let start_req = payload::request::StartGame{player_id : ids_a[0], game_id : game_b};
+            let res = start_game(start_req, db.clone(), map_catalog, 60.0, "127.0.0.1".to_string(), 8080).await.unwrap().into_response();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(get_state(&db, &game_b), GameState::Lobby);
+            assert_eq!(get_state(&db, &game_a), GameState::Lobby);
+        }
+
+        async fn get_body(res : reply::Response) -> serde_json::Value
+        {
+            let bytes = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice(&bytes).unwrap()
         }
 
-        game_players
-    }
+        #[tokio::test]
+        async fn waiting_for_channel_msgs_wakes_on_new_msg()
+        {
+            let db = database::DB::new();
+            let (_, ids) = create_lobby(&db, 2);
+            let limiter = RateLimiter::new(ratelimit::RateLimitConfig::default());
 
-    fn get_game_players_info(db : &database::DB, game_id : &uuid::Uuid) -> Vec<payload::response::PlayerInfo>
-    {
-        let mut game_players = Vec::new();
-        for entry in db.player_game_table.get_all().into_iter()
+            let gcm_req = payload::request::GetChannelMsgs{player_id : ids[0], target_id : None, after_id : 0, wait : true};
+            let waiting = tokio::spawn(get_channel_msgs(gcm_req, db.clone()));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let scm_req = payload::request::SendChannelMsg{player_id : ids[1], target_id : None, msg : "Hello".to_string()};
+            let _ = send_channel_msg(scm_req, db.clone(), None, limiter).await;
+
+            // Well before the 5 seconds the request waits at most
+            let res = tokio::time::timeout(Duration::from_secs(1), waiting).await.expect("Request was not woken").unwrap().unwrap();
+            let body = get_body(res.into_response()).await;
+            assert_eq!(body["msgs"][0]["text"], "Hello");
+        }
+
+        #[test]
+        fn ports_are_picked_per_host()
         {
-            let entry_game_id = entry.game_id;
-            
-            if *game_id == entry_game_id
-            {
-                let player = db.player_table.get(&entry.player_id).expect("Could not find player in playertable");
-                let ready = match entry.player_type {entity::PlayerType::Player(ready) => ready, _ => false};
-                let host = match entry.player_type{entity::PlayerType::Host => true, _ => false};
-                let player_info = payload::response::PlayerInfo{name : player.name, ready : ready, host : host};
-                game_players.push(player_info);
-            }
+            let db = database::DB::new();
+            let (game_id, _) = create_lobby(&db, 1);
+            let mut game = db.game_table.get(&game_id).unwrap();
+            game.port = Some(8000);
+            db.game_table.insert(game_id, game.clone());
+
+            assert!(is_port_in_use(&db, &game.host_id, 8000));
+            assert!(!is_port_in_use(&db, &game.host_id, 8001));
+            assert!(!is_port_in_use(&db, &uuid::Uuid::new_v4(), 8000));
         }
 
-        game_players
+        #[tokio::test]
+        async fn launches_when_countdown_ends()
+        {
+            let db = database::DB::new();
+            let (game_id, ids) = create_lobby(&db, 2);
+            toggle(&db, ids[1]).await;
+
+            let mut game = db.game_table.get(&game_id).unwrap();
+            game.state = GameState::Starting{deadline : get_unix_time_ms() - 1};
+            db.game_table.insert(game_id, game);
+
+            let maps_folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let map_catalog = MapCatalog::load(maps_folder.to_str().unwrap(), 1);
+            update_game_lifecycle(&db, game_id, &map_catalog, 60.0, "127.0.0.1", 8080);
+            assert_eq!(get_state(&db, &game_id), GameState::Running);
+            assert!(db.game_table.get(&game_id).unwrap().port.is_some());
+        }
     }
 }
 
@@ -854,132 +2974,372 @@ pub mod filters
     use super::handlers;
     use crate::matchmaking::payload::request;
     use crate::matchmaking::database;
+    use crate::matchmaking::maps::MapCatalog;
+    use crate::matchmaking::ratelimit::RateLimiter;
+    use regex::Regex;
 
-    pub fn get_routes(db : database::DB, exec_path : String, maps_folder : String, public_address : String, server_tickrate : f32, mm_port : u16) 
-        -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_routes(db : database::DB, map_catalog : MapCatalog, server_tickrate : f32, mm_address : String, mm_port : u16, chat_filter : Option<Regex>, 
+        admin_key : Option<String>, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone
+    {
+        login(db.clone(), limiter.clone())
+        .or(list_games(db.clone(), limiter.clone()))
+        .or(create_game(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(edit_game(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(join_game(db.clone(), limiter.clone()))
+        .or(quick_play(db.clone(), limiter.clone()))
+        .or(leave_game(db.clone(), limiter.clone()))
+        .or(toggle_ready(db.clone(), limiter.clone()))
+        .or(kick_player(db.clone(), limiter.clone()))
+        .or(mute_player(db.clone(), limiter.clone()))
+        .or(ban_player(db.clone(), limiter.clone()))
+        .or(transfer_host(db.clone(), limiter.clone()))
+        .or(select_team(db.clone(), limiter.clone()))
+        .or(lock_teams(db.clone(), limiter.clone()))
+        .or(shuffle_teams(db.clone(), limiter.clone()))
+        .or(balance_teams(db.clone(), limiter.clone()))
+        .or(send_chat_msg(db.clone(), chat_filter.clone(), limiter.clone()))
+        .or(get_chat(db.clone(), limiter.clone()))
+        .or(send_channel_msg(db.clone(), chat_filter, limiter.clone()))
+        .or(get_channel_msgs(db.clone(), limiter.clone()))
+        .or(update_game(db.clone(), limiter.clone()))
+        .or(start_game(db.clone(), map_catalog.clone(), server_tickrate, mm_address, mm_port, limiter.clone()))
+        .or(get_available_maps(map_catalog.clone(), limiter.clone()))
+        .or(download_map(map_catalog.clone(), limiter.clone()))
+        .or(get_map_picture(map_catalog.clone(), limiter.clone()))
+        .or(get_map_zip(map_catalog.clone(), limiter.clone()))
+        .or(get_map_picture_file(map_catalog.clone(), limiter.clone()))
+        .or(upload_map(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(upload_map_picture(map_catalog.clone(), limiter.clone()))
+        .or(rollback_map(map_catalog.clone(), limiter.clone()))
+        .or(delete_map(db.clone(), map_catalog.clone(), admin_key.clone(), limiter.clone()))
+        .or(rename_map(db.clone(), map_catalog.clone(), admin_key.clone(), limiter.clone()))
+        .or(admin_list_maps(db.clone(), map_catalog.clone(), admin_key, limiter.clone()))
+        .or(start_map_upload(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(upload_map_chunk(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(get_map_upload(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(commit_map_upload(db.clone(), map_catalog.clone(), limiter.clone()))
+        .or(notify_server_event(db.clone(), limiter.clone()))
+        .or(list_hosts(db.clone(), limiter.clone()))
+        .or(report_ping(db.clone(), limiter))
+        .recover(handlers::handle_rejection)
+    }
+
+    // Rejects the request if its client went over the route's limit
+    fn limit_ip(limiter : RateLimiter, route : &'static str) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone
+    {
+        warp::addr::remote()
+        .and_then(move |address : Option<std::net::SocketAddr>| {
+            let res = match address {
+                Some(address) => limiter.check_ip(route, address.ip()),
+                None => Ok(()),
+            };
+            async move { res.map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+    }
+
+    // Parses the body and rejects the request if its player went over the route's limit
+    fn limited_json_body<T>(limiter : RateLimiter, route : &'static str, max_len : u64) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+        where T : request::PlayerRequest + serde::de::DeserializeOwned + Send
+    {
+        warp::body::content_length_limit(max_len)
+        .and(warp::body::json::<T>())
+        .and_then(move |req : T| {
+            let res = match req.get_player_id() {
+                Some(player_id) => limiter.check_player(route, player_id),
+                None => Ok(()),
+            };
+            async move { res.map(|_| req).map_err(warp::reject::custom) }
+        })
+    }
+
+    // Same as limited_json_body, but requests without a body get the default one
+    fn optional_json_body<T>(limiter : RateLimiter, route : &'static str, max_len : u64) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+        where T : request::PlayerRequest + serde::de::DeserializeOwned + Default + Send
     {
-        login(db.clone())
-        .or(list_games(db.clone()))
-        .or(create_game(db.clone(), maps_folder.clone()))
-        .or(edit_game(db.clone(), maps_folder.clone()))
-        .or(join_game(db.clone()))
-        .or(leave_game(db.clone()))
-        .or(toggle_ready(db.clone()))
-        .or(send_chat_msg(db.clone()))
-        .or(update_game(db.clone()))
-        .or(start_game(db.clone(), exec_path, maps_folder.clone(), public_address.clone(), server_tickrate, mm_port))
-        .or(get_available_maps(maps_folder.clone()))
-        .or(download_map(maps_folder.clone()))
-        .or(get_map_picture(maps_folder.clone()))
-        .or(upload_map(maps_folder.clone()))
-        .or(notify_server_event(db.clone()))
-    }
-
-    pub fn login(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        warp::header::optional::<u64>("content-length")
+        .and_then(|len : Option<u64>| async move {
+            match len.unwrap_or(0)
+            {
+                0 => Ok(T::default()),
+                _ => Err(warp::reject()),
+            }
+        })
+        .or(limited_json_body::<T>(limiter, route, max_len))
+        .unify()
+    }
+
+    pub fn login(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
 
         warp::post()
         .and(warp::path("login"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json::<request::Login>()))
+        .and(limit_ip(limiter.clone(), "login"))
+        .and(limited_json_body::<request::Login>(limiter, "login", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::login)
     }
 
-    pub fn list_games(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn list_games(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
         warp::post()
         .and(warp::path("list_games"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json::<serde_json::Value>()))
+        .and(limit_ip(limiter.clone(), "list_games"))
+        .and(limited_json_body::<request::ListGames>(limiter, "list_games", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::list_games)
     }
 
-    pub fn join_game(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn join_game(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
 
         warp::post()
         .and(warp::path("join_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json::<request::JoinGame>()))
+        .and(limit_ip(limiter.clone(), "join_game"))
+        .and(limited_json_body::<request::JoinGame>(limiter, "join_game", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::join_game)
     }
 
-    pub fn create_game(db : database::DB, maps_folder : String) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn quick_play(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+
+        warp::post()
+        .and(warp::path("quick_play"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "quick_play"))
+        .and(limited_json_body::<request::QuickPlay>(limiter, "quick_play", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::quick_play)
+    }
+
+    pub fn create_game(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
-        let param3 = warp::any().map(move || maps_folder.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("create_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::CreateGame>())
+        .and(limit_ip(limiter.clone(), "create_game"))
+        .and(limited_json_body::<request::CreateGame>(limiter, "create_game", 1024 * 16))
         .and(filter.clone())
         .and(param3.clone())
         .and_then(handlers::create_game)
     }
 
-    pub fn edit_game(db : database::DB, maps_folder : String) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn edit_game(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
-        let param3 = warp::any().map(move || maps_folder.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("edit_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::EditGame>())
+        .and(limit_ip(limiter.clone(), "edit_game"))
+        .and(limited_json_body::<request::EditGame>(limiter, "edit_game", 1024 * 16))
         .and(filter.clone())
         .and(param3.clone())
         .and_then(handlers::edit_game)
     }
 
-    pub fn leave_game(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn leave_game(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
         
         warp::post()
         .and(warp::path("leave_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::LeaveGame>())
+        .and(limit_ip(limiter.clone(), "leave_game"))
+        .and(limited_json_body::<request::LeaveGame>(limiter, "leave_game", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::leave_game)
     }
 
-    pub fn toggle_ready(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn toggle_ready(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
         
         warp::post()
         .and(warp::path("toggle_ready"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::ToggleReady>())
+        .and(limit_ip(limiter.clone(), "toggle_ready"))
+        .and(limited_json_body::<request::ToggleReady>(limiter, "toggle_ready", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::toggle_ready)
     }
 
-    pub fn send_chat_msg(db : database::DB) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    pub fn kick_player(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("kick_player"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "kick_player"))
+        .and(limited_json_body::<request::KickPlayer>(limiter, "kick_player", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::kick_player)
+    }
+
+    pub fn mute_player(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("mute_player"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "mute_player"))
+        .and(limited_json_body::<request::MutePlayer>(limiter, "mute_player", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::mute_player)
+    }
+
+    pub fn ban_player(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("ban_player"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "ban_player"))
+        .and(limited_json_body::<request::BanPlayer>(limiter, "ban_player", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::ban_player)
+    }
+
+    pub fn transfer_host(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("transfer_host"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "transfer_host"))
+        .and(limited_json_body::<request::TransferHost>(limiter, "transfer_host", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::transfer_host)
+    }
+
+    pub fn select_team(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("select_team"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "select_team"))
+        .and(limited_json_body::<request::SelectTeam>(limiter, "select_team", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::select_team)
+    }
+
+    pub fn lock_teams(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("lock_teams"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "lock_teams"))
+        .and(limited_json_body::<request::LockTeams>(limiter, "lock_teams", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::lock_teams)
+    }
+
+    pub fn shuffle_teams(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("shuffle_teams"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "shuffle_teams"))
+        .and(limited_json_body::<request::ShuffleTeams>(limiter, "shuffle_teams", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::shuffle_teams)
+    }
+
+    pub fn balance_teams(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("balance_teams"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "balance_teams"))
+        .and(limited_json_body::<request::BalanceTeams>(limiter, "balance_teams", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::balance_teams)
+    }
+
+    pub fn send_chat_msg(db : database::DB, chat_filter : Option<Regex>, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || chat_filter.clone());
         
         warp::post()
         .and(warp::path("send_chat_msg"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::SendChatMsg>())
+        .and(limit_ip(limiter.clone(), "send_chat_msg"))
+        .and(limited_json_body::<request::SendChatMsg>(limiter.clone(), "send_chat_msg", 1024 * 16))
         .and(filter.clone())
+        .and(param3)
+        .and(warp::any().map(move || limiter.clone()))
         .and_then(handlers::send_chat_msg)
     }
 
-    pub fn update_game(db : database::DB)
+    pub fn send_channel_msg(db : database::DB, chat_filter : Option<Regex>, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || chat_filter.clone());
+        
+        warp::post()
+        .and(warp::path("send_channel_msg"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "send_channel_msg"))
+        .and(limited_json_body::<request::SendChannelMsg>(limiter.clone(), "send_channel_msg", 1024 * 16))
+        .and(filter.clone())
+        .and(param3)
+        .and(warp::any().map(move || limiter.clone()))
+        .and_then(handlers::send_channel_msg)
+    }
+
+    pub fn get_channel_msgs(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("get_channel_msgs"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "get_channel_msgs"))
+        .and(limited_json_body::<request::GetChannelMsgs>(limiter, "get_channel_msgs", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::get_channel_msgs)
+    }
+
+    pub fn get_chat(db : database::DB, limiter : RateLimiter) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        
+        warp::post()
+        .and(warp::path("get_chat"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "get_chat"))
+        .and(limited_json_body::<request::GetChat>(limiter, "get_chat", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::get_chat)
+    }
+
+    pub fn update_game(db : database::DB, limiter : RateLimiter)
         -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
@@ -987,91 +3347,274 @@ pub mod filters
         warp::post()
         .and(warp::path("update_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::UpdateGame>())
+        .and(limit_ip(limiter.clone(), "update_game"))
+        .and(limited_json_body::<request::UpdateGame>(limiter, "update_game", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::update_game)
     }
 
-    pub fn start_game(db : database::DB, exec_path : String, maps_folder : String, public_address : String, server_tickrate : f32, mm_port : u16)
+    pub fn start_game(db : database::DB, map_catalog : MapCatalog, server_tickrate : f32, mm_address : String, mm_port : u16, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
-        let param2 = warp::any().map(move || exec_path.clone());
-        let param3 = warp::any().map(move || maps_folder.clone());
-        let param4 = warp::any().map(move || public_address.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+        let param4 = warp::any().map(move || mm_address.clone());
         let param5 = warp::any().map(move || mm_port);
         let tickrate = warp::any().map(move || server_tickrate);
 
         warp::post()
         .and(warp::path("start_game"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json::<request::StartGame>())
+        .and(limit_ip(limiter.clone(), "start_game"))
+        .and(limited_json_body::<request::StartGame>(limiter, "start_game", 1024 * 16))
         .and(filter.clone())
-        .and(param2.clone())
         .and(param3.clone())
-        .and(param4.clone())
-        .and(tickrate.clone())
-        .and(param5.clone())
+        .and(tickrate)
+        .and(param4)
+        .and(param5)
         .and_then(handlers::start_game)
     }
 
-    pub fn get_available_maps(maps_folder : String)
+    pub fn get_available_maps(map_catalog : MapCatalog, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
-        let filter = warp::any().map(move || maps_folder.clone());
+        let filter = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("get_available_maps"))
         .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "get_available_maps"))
+        .and(optional_json_body::<request::GetAvailableMaps>(limiter, "get_available_maps", 1024 * 16))
         .and(filter.clone())
         .and_then(handlers::get_available_maps)
     }
 
-    pub fn download_map(maps_folder : String)
+    pub fn download_map(map_catalog : MapCatalog, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
-        let filter = warp::any().map(move || maps_folder.clone());
+        let filter = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("download_map"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 32))
-        .and(warp::body::json::<request::DownloadMap>())
+        .and(limit_ip(limiter.clone(), "download_map"))
+        .and(limited_json_body::<request::DownloadMap>(limiter, "download_map", 1024 * 32))
         .and(filter.clone())
         .and_then(handlers::download_map)
     }
 
-    pub fn get_map_picture(maps_folder : String)
+    pub fn get_map_zip(map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || map_catalog.clone());
+
+        // HEAD lets clients check the size and ETag without downloading the file
+        warp::get().or(warp::head()).unify()
+        .and(warp::path("maps"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(limit_ip(limiter, "get_map_zip"))
+        .and(warp::header::headers_cloned())
+        .and(filter.clone())
+        .and_then(handlers::get_map_zip)
+    }
+
+    pub fn get_map_picture_file(map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || map_catalog.clone());
+
+        warp::get().or(warp::head()).unify()
+        .and(warp::path("maps"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("pictures"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(limit_ip(limiter, "get_map_picture_file"))
+        .and(warp::header::headers_cloned())
+        .and(filter.clone())
+        .and_then(handlers::get_map_picture_file)
+    }
+
+    pub fn get_map_picture(map_catalog : MapCatalog, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
-        let map_folder = warp::any().map(move || maps_folder.clone());
+        let map_folder = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("get_map_picture"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 64))
-        .and(warp::body::json::<request::MapPicture>())
+        .and(limit_ip(limiter.clone(), "get_map_picture"))
+        .and(limited_json_body::<request::MapPicture>(limiter, "get_map_picture", 1024 * 64))
         .and(map_folder)
         .and_then(handlers::get_map_picture)
     }
 
-    pub fn upload_map(maps_folder : String)
+    pub fn upload_map(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
-        let filter = warp::any().map(move || maps_folder.clone());
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
 
         warp::post()
         .and(warp::path("upload_map"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 1024 * 16)) // 16 MB
-        .and(warp::body::json::<request::UploadMap>())
+        .and(limit_ip(limiter.clone(), "upload_map"))
+        .and(limited_json_body::<request::UploadMap>(limiter, "upload_map", 1024 * 1024 * 16)) // 16 MB
         .and(filter.clone())
+        .and(param3.clone())
         .and_then(handlers::upload_map)
     }
 
-    pub fn notify_server_event(db : database::DB)
+    pub fn upload_map_picture(map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("upload_map_picture"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "upload_map_picture"))
+        .and(limited_json_body::<request::UploadMapPicture>(limiter, "upload_map_picture", 1024 * 1024 * 6)) // 6 MB
+        .and(filter.clone())
+        .and_then(handlers::upload_map_picture)
+    }
+
+    pub fn rollback_map(map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("rollback_map"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "rollback_map"))
+        .and(limited_json_body::<request::RollbackMap>(limiter, "rollback_map", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::rollback_map)
+    }
+
+    pub fn delete_map(db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+        let param4 = warp::any().map(move || admin_key.clone());
+
+        warp::post()
+        .and(warp::path("delete_map"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "delete_map"))
+        .and(limited_json_body::<request::DeleteMap>(limiter, "delete_map", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and(param4.clone())
+        .and_then(handlers::delete_map)
+    }
+
+    pub fn rename_map(db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+        let param4 = warp::any().map(move || admin_key.clone());
+
+        warp::post()
+        .and(warp::path("rename_map"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "rename_map"))
+        .and(limited_json_body::<request::RenameMap>(limiter, "rename_map", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and(param4.clone())
+        .and_then(handlers::rename_map)
+    }
+
+    pub fn admin_list_maps(db : database::DB, map_catalog : MapCatalog, admin_key : Option<String>, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+        let param4 = warp::any().map(move || admin_key.clone());
+
+        warp::post()
+        .and(warp::path("admin_list_maps"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "admin_list_maps"))
+        .and(limited_json_body::<request::AdminListMaps>(limiter, "admin_list_maps", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and(param4.clone())
+        .and_then(handlers::admin_list_maps)
+    }
+
+    pub fn start_map_upload(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("start_map_upload"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "start_map_upload"))
+        .and(limited_json_body::<request::StartMapUpload>(limiter, "start_map_upload", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and(warp::addr::remote())
+        .and_then(handlers::start_map_upload)
+    }
+
+    pub fn upload_map_chunk(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("upload_map_chunk"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "upload_map_chunk"))
+        .and(limited_json_body::<request::UploadMapChunk>(limiter, "upload_map_chunk", 1024 * 1024 * 2)) // Base64 chunk, with room to spare
+        .and(filter.clone())
+        .and(param3.clone())
+        .and_then(handlers::upload_map_chunk)
+    }
+
+    pub fn get_map_upload(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("get_map_upload"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "get_map_upload"))
+        .and(limited_json_body::<request::MapUpload>(limiter, "get_map_upload", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and_then(handlers::get_map_upload)
+    }
+
+    pub fn commit_map_upload(db : database::DB, map_catalog : MapCatalog, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+        let param3 = warp::any().map(move || map_catalog.clone());
+
+        warp::post()
+        .and(warp::path("commit_map_upload"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "commit_map_upload"))
+        .and(limited_json_body::<request::MapUpload>(limiter, "commit_map_upload", 1024 * 16))
+        .and(filter.clone())
+        .and(param3.clone())
+        .and_then(handlers::commit_map_upload)
+    }
+
+    pub fn notify_server_event(db : database::DB, limiter : RateLimiter)
     -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
     {
         let filter = warp::any().map(move || db.clone());
@@ -1079,9 +3622,36 @@ pub mod filters
         warp::post()
         .and(warp::path("notify_server_event"))
         .and(warp::path::end())
-        .and(warp::body::content_length_limit(1024 * 64))
-        .and(warp::body::json::<request::NotifyServerEvent>())
+        .and(limit_ip(limiter.clone(), "notify_server_event"))
+        .and(limited_json_body::<request::NotifyServerEvent>(limiter, "notify_server_event", 1024 * 64))
         .and(filter.clone())
         .and_then(handlers::notify_server_event)
     }
+
+    pub fn list_hosts(db : database::DB, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+
+        warp::post()
+        .and(warp::path("list_hosts"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter, "list_hosts"))
+        .and(filter.clone())
+        .and_then(handlers::list_hosts)
+    }
+
+    pub fn report_ping(db : database::DB, limiter : RateLimiter)
+    -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        let filter = warp::any().map(move || db.clone());
+
+        warp::post()
+        .and(warp::path("report_ping"))
+        .and(warp::path::end())
+        .and(limit_ip(limiter.clone(), "report_ping"))
+        .and(limited_json_body::<request::ReportPing>(limiter, "report_ping", 1024 * 16))
+        .and(filter.clone())
+        .and_then(handlers::report_ping)
+    }
 }
\ No newline at end of file
@@ -1,11 +1,16 @@
 
 
-use ringbuffer::AllocRingBuffer;
+use std::collections::HashMap;
+
+use ringbuffer::{AllocRingBuffer, RingBufferExt, RingBufferWrite};
 use serde::{Deserialize, Serialize};
 
+use super::payload::request::MapMetadata;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Player{
-    pub id : uuid::Uuid,
+    pub id : uuid::Uuid, // Session id. Only known by the player, as it's used to act on its behalf
+    pub public_id : uuid::Uuid, // Shown to other players, so they can refer to this one
     pub name : String
 }
 
@@ -20,15 +25,130 @@ impl Player{
         let name = username + "#" + id_chars;
         Player{
             id,
+            public_id : uuid::Uuid::new_v4(),
             name,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+pub fn get_unix_time_ms() -> u64
+{
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum GameState{
-    InLobby,
-    InGame
+    Lobby,
+    Starting{ deadline : u64 }, // Unix time in ms at which the game will be launched
+    Launching,
+    Running,
+    PostGame,
+    Closed,
+    Failed,
+}
+
+impl GameState{
+
+    // Lifecycle: Lobby <-> Starting -> Launching -> Running -> PostGame -> Lobby. Launching and Running may end up in Failed,
+    // if the server can't be launched or stops in the middle of the game, which goes back to Lobby. Any state can be Closed.
+    pub fn can_transition_to(&self, next : &GameState) -> bool
+    {
+        use GameState::*;
+        match (self, next)
+        {
+            (Closed, _) => false,
+            (_, Closed) => true,
+            (Lobby, Starting{..}) | (Lobby, Launching) => true,
+            (Starting{..}, Lobby) | (Starting{..}, Launching) => true,
+            (Launching, Running) | (Launching, Failed) => true,
+            (Running, PostGame) | (Running, Failed) => true,
+            (PostGame, Lobby) | (Failed, Lobby) => true,
+            _ => false,
+        }
+    }
+
+    // Players gather and the host can change the game settings
+    pub fn is_lobby(&self) -> bool
+    {
+        matches!(self, GameState::Lobby | GameState::Starting{..})
+    }
+
+    // New spectators can join. Players can only join while it's in lobby
+    pub fn is_joinable(&self) -> bool
+    {
+        matches!(self, GameState::Lobby | GameState::Starting{..} | GameState::Running | GameState::PostGame)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StateTransition{
+    pub from : GameState,
+    pub to : GameState,
+    pub time : u64, // Unix time in ms
+}
+
+#[derive(Debug)]
+pub struct InvalidTransition{
+    pub from : GameState,
+    pub to : GameState,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum ChatMsgKind{
+    Player,
+    System,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatMsg{
+    pub id : u64, // Increases with every message of a chat log
+    pub sender_id : Option<uuid::Uuid>, // Public id of the sender. None for system messages
+    pub sender_name : String,
+    pub timestamp : u64, // Unix time in ms
+    pub kind : ChatMsgKind,
+    pub text : String,
+}
+
+// Latest messages of a game or channel
+#[derive(Debug, Clone)]
+pub struct ChatLog{
+    msgs : AllocRingBuffer<ChatMsg>,
+    next_id : u64,
+}
+
+impl ChatLog{
+
+    pub fn new(capacity : usize) -> ChatLog{
+        ChatLog{
+            msgs : AllocRingBuffer::with_capacity(capacity),
+            next_id : 1,
+        }
+    }
+
+    pub fn push(&mut self, sender : Option<&Player>, kind : ChatMsgKind, text : String)
+    {
+        let msg = ChatMsg{
+            id : self.next_id,
+            sender_id : sender.map(|x| x.public_id),
+            sender_name : sender.map(|x| x.name.clone()).unwrap_or_else(|| "System".to_string()),
+            timestamp : get_unix_time_ms(),
+            kind,
+            text,
+        };
+        self.next_id += 1;
+        self.msgs.push(msg);
+    }
+
+    // Messages still in the buffer with an id greater than the given one
+    pub fn get_after(&self, after_id : u64) -> Vec<ChatMsg>
+    {
+        self.msgs.iter().filter(|msg| msg.id > after_id).cloned().collect()
+    }
+
+    pub fn to_vec(&self) -> Vec<ChatMsg>
+    {
+        self.msgs.to_vec()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,11 +158,20 @@ pub struct Game{
     pub name : String,
     pub map : String,
     pub map_version : String,
+    pub map_zip_sha256 : Option<String>,
+    pub map_picture_sha256 : Option<String>,
     pub mode : String,
     pub max_players : u8,
-    pub chat : ringbuffer::AllocRingBuffer<String>,
+    pub max_spectators : u8,
+    pub chat : ChatLog,
+    pub host_id : uuid::Uuid,
+    pub banned : Vec<uuid::Uuid>,
+    pub muted : Vec<uuid::Uuid>,
+    pub teams_locked : bool,
+    pub auto_start : bool,
 
     pub state : GameState,
+    pub state_history : Vec<StateTransition>,
     pub address : Option<String>,
     pub port : Option<u16>,
 
@@ -51,30 +180,62 @@ pub struct Game{
 
 impl Game{
     
-    pub fn new(name : String, map : String, map_version : String, mode : String, max_players : u8) -> Game{
+    pub fn new(name : String, map : String, map_version : String, mode : String, max_players : u8, max_spectators : u8, host_id : uuid::Uuid) -> Game{
         Game{
             id : uuid::Uuid::new_v4(),
             key : uuid::Uuid::new_v4(),
             name,
             map,
             map_version,
+            map_zip_sha256 : None,
+            map_picture_sha256 : None,
             mode,
             max_players,
-            chat : AllocRingBuffer::with_capacity(16),
+            max_spectators,
+            chat : ChatLog::new(16),
+            host_id,
+            banned : Vec::new(),
+            muted : Vec::new(),
+            teams_locked : false,
+            auto_start : false,
             
-            state : GameState::InLobby,
+            state : GameState::Lobby,
+            state_history : Vec::new(),
             address : None,
             port : None,
             
             last_update : std::time::SystemTime::now()
         }
     }
+
+    // Every state change goes through here, so invalid ones are rejected and valid ones are recorded
+    pub fn set_state(&mut self, state : GameState) -> Result<(), InvalidTransition>
+    {
+        if !self.state.can_transition_to(&state)
+        {
+            return Err(InvalidTransition{from : self.state.clone(), to : state});
+        }
+
+        let from = std::mem::replace(&mut self.state, state.clone());
+        self.state_history.push(StateTransition{from, to : state, time : get_unix_time_ms()});
+        self.last_update = std::time::SystemTime::now();
+
+        Ok(())
+    }
+
+    // Time in ms since the last state change
+    pub fn get_state_elapsed(&self) -> u64
+    {
+        let since = self.state_history.last().map(|x| x.time).unwrap_or(0);
+        get_unix_time_ms().saturating_sub(since)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum PlayerType{
     Host,
-    Player(bool)
+    Player(bool),
+    Spectator,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -82,6 +243,8 @@ pub struct PlayerGame{
     pub player_id : uuid::Uuid,
     pub game_id : uuid::Uuid,
     pub player_type : PlayerType,
+    pub joined_at : std::time::SystemTime,
+    pub team : Option<u8>,
 }
 
 impl PlayerGame{
@@ -92,6 +255,8 @@ impl PlayerGame{
             player_id,
             game_id,
             player_type : PlayerType::Player(false),
+            joined_at : std::time::SystemTime::now(),
+            team : None,
         }
     }
 
@@ -101,12 +266,26 @@ impl PlayerGame{
             player_id,
             game_id,
             player_type : PlayerType::Host,
+            joined_at : std::time::SystemTime::now(),
+            team : None,
+        }
+    }
+
+    pub fn new_spectator(player_id : uuid::Uuid, game_id : uuid::Uuid) -> PlayerGame
+    {
+        PlayerGame{
+            player_id,
+            game_id,
+            player_type : PlayerType::Spectator,
+            joined_at : std::time::SystemTime::now(),
+            team : None,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct GameSem{
+    #[allow(dead_code)]
     pub game_id : uuid::Uuid,
     pub sem : std::sync::Arc<std::sync::Condvar>,
     pub mutex : std::sync::Arc<std::sync::Mutex<i32>>,
@@ -123,3 +302,160 @@ impl GameSem{
     }
 }
 
+// Chat outside of games. The global channel is seen by every player, other channels hold direct messages between two players
+#[derive(Debug, Clone)]
+pub struct ChatChannel{
+    pub id : uuid::Uuid,
+    pub members : Vec<uuid::Uuid>, // Empty for the global channel
+    pub chat : ChatLog,
+    pub notify : std::sync::Arc<tokio::sync::Notify>, // Wakes the requests waiting for messages of this channel
+}
+
+impl ChatChannel{
+
+    pub const GLOBAL_ID : uuid::Uuid = uuid::Uuid::nil();
+
+    pub fn new_global() -> ChatChannel{
+        ChatChannel::new(ChatChannel::GLOBAL_ID, Vec::new(), 64)
+    }
+
+    pub fn new_direct(player_a : uuid::Uuid, player_b : uuid::Uuid) -> ChatChannel{
+        ChatChannel::new(ChatChannel::get_direct_id(&player_a, &player_b), vec![player_a, player_b], 32)
+    }
+
+    // Same id no matter which of the two players asks for it
+    pub fn get_direct_id(player_a : &uuid::Uuid, player_b : &uuid::Uuid) -> uuid::Uuid
+    {
+        uuid::Uuid::from_u128(player_a.as_u128() ^ player_b.as_u128())
+    }
+
+    fn new(id : uuid::Uuid, members : Vec<uuid::Uuid>, capacity : usize) -> ChatChannel{
+        ChatChannel{
+            id,
+            members,
+            chat : ChatLog::new(capacity),
+            notify : std::sync::Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GameHost{
+    pub id : uuid::Uuid,
+    pub name : String,
+    pub region : String,
+    pub address : String, // Public address given to clients
+    pub ping_port : u16,
+    pub server_path : String, // Executable used to launch game servers on this host
+}
+
+impl GameHost{
+
+    pub fn new(name : String, region : String, address : String, ping_port : u16, server_path : String) -> GameHost{
+        GameHost{
+            id : uuid::Uuid::new_v4(),
+            name,
+            region,
+            address,
+            ping_port,
+            server_path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlayerPing{
+    pub pings : HashMap<uuid::Uuid, u16>, // Host id => RTT in ms
+}
+
+// Map upload sent in chunks. Chunks are stored on disk until the upload is committed
+#[derive(Debug, Clone)]
+pub struct MapUpload{
+    pub id : uuid::Uuid,
+    pub map_name : String,
+    pub password : String,
+    pub ip : Option<std::net::IpAddr>, // Of the client which started the upload
+    pub map_version : String,
+    pub metadata : MapMetadata,
+    pub size : u64,
+    pub sha256 : String, // Lowercase hex
+    pub last_update : u64, // Unix time in ms. Used to remove abandoned uploads
+}
+
+impl MapUpload{
+
+    pub fn new(map_name : String, password : String, ip : Option<std::net::IpAddr>, map_version : String, metadata : MapMetadata, 
+        size : u64, sha256 : String) -> MapUpload{
+        MapUpload{
+            id : uuid::Uuid::new_v4(),
+            map_name,
+            password,
+            ip,
+            map_version,
+            metadata,
+            size,
+            sha256,
+            last_update : get_unix_time_ms(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::GameState;
+    use super::GameState::*;
+
+    const STATES : [GameState; 7] = [Lobby, Starting{ deadline : 0 }, Launching, Running, PostGame, Closed, Failed];
+
+    #[test]
+    fn allows_lifecycle_transitions()
+    {
+        let allowed = [
+            (Lobby, Starting{ deadline : 0 }), (Lobby, Launching),
+            (Starting{ deadline : 0 }, Lobby), (Starting{ deadline : 0 }, Launching),
+            (Launching, Running), (Launching, Failed),
+            (Running, PostGame), (Running, Failed),
+            (PostGame, Lobby), (Failed, Lobby),
+        ];
+        for (from, to) in allowed.iter()
+        {
+            assert!(from.can_transition_to(to), "{:?} -> {:?}", from, to);
+        }
+    }
+
+    #[test]
+    fn rejects_skipped_or_reversed_transitions()
+    {
+        let rejected = [
+            (Lobby, Running), (Lobby, PostGame), (Lobby, Failed), (Lobby, Lobby),
+            (Starting{ deadline : 0 }, Running), (Starting{ deadline : 0 }, Starting{ deadline : 1 }),
+            (Launching, Lobby), (Launching, PostGame),
+            (Running, Lobby), (Running, Launching),
+            (PostGame, Running), (PostGame, Starting{ deadline : 0 }),
+            (Failed, Launching), (Failed, Running),
+        ];
+        for (from, to) in rejected.iter()
+        {
+            assert!(!from.can_transition_to(to), "{:?} -> {:?}", from, to);
+        }
+    }
+
+    #[test]
+    fn any_state_can_close()
+    {
+        for state in STATES.iter().filter(|x| !matches!(x, Closed))
+        {
+            assert!(state.can_transition_to(&Closed), "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn closed_is_final()
+    {
+        for state in STATES.iter()
+        {
+            assert!(!Closed.can_transition_to(state), "{:?}", state);
+        }
+    }
+}
@@ -0,0 +1,1147 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageError, ImageFormat};
+use notify::{RecursiveMode, Watcher};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+use zip::ZipArchive;
+
+use super::entity::get_unix_time_ms;
+use super::payload::request::MapMetadata;
+use super::payload::response::{Error, ErrorCode, MapInfo};
+
+const MAX_MAP_ENTRIES : usize = 256;
+const MAX_MAP_SIZE : u64 = 64 * 1024 * 1024; // Uncompressed size of every file in a map archive
+const UPLOADS_FOLDER : &str = ".uploads";
+const VERSIONS_FOLDER : &str = "versions";
+const VERSION_CONFIG : &str = "version.yml";
+const MAX_AUTHOR_LEN : usize = 32;
+const MAX_DESCRIPTION_LEN : usize = 512;
+const MAX_TAGS : usize = 8;
+const PICTURES_FOLDER : &str = "pictures";
+const MAX_PICTURE_DIMENSION : u32 = 4096;
+const PICTURE_QUALITY : u8 = 85;
+pub const MAX_PICTURE_SIZE : usize = 4 * 1024 * 1024;
+pub const THUMBNAIL_SIZES : [u32; 3] = [64, 128, 256]; // Thumbnails fit in a square of each size
+
+#[derive(Debug, Clone)]
+pub struct MapVersion{
+    pub version : String,
+    pub metadata : MapMetadata,
+    pub uploaded_at : u64, // Unix time in ms
+    pub zip_size : Option<u64>, // None if the zip file is missing
+    pub zip_sha256 : Option<String>, // Lowercase hex, None if the file is missing
+    pub picture_sha256 : Option<String>,
+}
+
+// Who can update a map: anyone who knows its password. Player ids only last for a session, so they can't own maps
+#[derive(Debug, Clone, Default)]
+pub struct MapAccess{
+    pub password_hash : String, // Salted Argon2 hash, in PHC format. Empty if the map has no password
+}
+
+impl MapAccess{
+
+    pub fn new(password : &str) -> Result<MapAccess, Error>
+    {
+        let password_hash = if password.is_empty() { String::new() } else { hash_password(password)? };
+        Ok(MapAccess{password_hash})
+    }
+
+    // The hash is compared in constant time by the verifier
+    pub fn is_allowed(&self, password : &str) -> bool
+    {
+        !self.password_hash.is_empty() && !password.is_empty() && PasswordHash::new(&self.password_hash)
+            .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+            .is_ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MapEntry{
+    pub info : MapInfo, // Info of the current version
+    pub access : MapAccess, // Required to update the map
+    pub versions : Vec<MapVersion>, // Oldest first
+}
+
+impl MapEntry{
+
+    pub fn get_version(&self, version : &str) -> Option<&MapVersion>
+    {
+        self.versions.iter().find(|x| x.version == version)
+    }
+}
+
+// Cached info of the maps in the maps folder. Maps which failed to load keep their error,
+// so requests for them can tell broken maps apart from missing ones.
+// Each map folder holds its config, with the password hash and current version, and a folder for each stored version.
+// Versions are never modified once uploaded, so games can keep using the one they were created with
+#[derive(Clone)]
+pub struct MapCatalog{
+    maps_folder : PathBuf,
+    versions_kept : usize,
+    maps : Arc<Mutex<HashMap<String, Result<MapEntry, Error>>>>,
+    reload_lock : Arc<Mutex<()>>,
+    use_lock : Arc<Mutex<()>>,
+    config_lock : Arc<Mutex<()>>, // Held while map configs are changed
+}
+
+impl MapCatalog{
+
+    pub fn load(maps_folder : &str, versions_kept : usize) -> MapCatalog
+    {
+        let catalog = MapCatalog{
+            maps_folder : PathBuf::from(maps_folder),
+            versions_kept,
+            maps : Arc::new(Mutex::new(HashMap::new())),
+            reload_lock : Arc::new(Mutex::new(())),
+            use_lock : Arc::new(Mutex::new(())),
+            config_lock : Arc::new(Mutex::new(())),
+        };
+
+        // Uploads don't survive restarts, so their chunks are no longer needed
+        let uploads_folder = catalog.maps_folder.join(UPLOADS_FOLDER);
+        if uploads_folder.exists()
+        {
+            if let Err(err) = std::fs::remove_dir_all(&uploads_folder)
+            {
+                println!("Could not remove uploads folder: {}", err);
+            }
+        }
+
+        match catalog.maps_folder.read_dir()
+        {
+            Ok(paths) => {
+                let maps = paths.filter_map(|r| r.ok())
+                    .map(|r| r.path())
+                    .filter(|r| r.is_dir())
+                    .filter_map(|r| r.file_name().and_then(|x| x.to_str()).map(|x| x.to_string()))
+                    .filter(|x| is_map_name_valid(x));
+                for map in maps
+                {
+                    catalog.reload_map(&map);
+                }
+            },
+            Err(err) => println!("Could not read maps folder: {}", err),
+        }
+
+        catalog
+    }
+
+    pub fn get_map_folder(&self, map : &str) -> PathBuf
+    {
+        self.maps_folder.join(map)
+    }
+
+    pub fn get_version_folder(&self, map : &str, version : &str) -> PathBuf
+    {
+        self.get_map_folder(map).join(VERSIONS_FOLDER).join(version)
+    }
+
+    pub fn get_zip_path(&self, map : &str, version : &str) -> PathBuf
+    {
+        self.get_version_folder(map, version).join(map.to_string() + ".zip")
+    }
+
+    pub fn get_picture_path(&self, map : &str, version : &str) -> PathBuf
+    {
+        self.get_version_folder(map, version).join(map.to_string() + ".jpg")
+    }
+
+    // Picture uploaded on its own, or one of its thumbnails. It's shared by every version and replaces the one in their zips
+    pub fn get_uploaded_picture_path(&self, map : &str, size : Option<u32>) -> PathBuf
+    {
+        let name = match size
+        {
+            Some(size) => format!("picture_{}.jpg", size),
+            None => "picture.jpg".to_string(),
+        };
+        self.get_map_folder(map).join(PICTURES_FOLDER).join(name)
+    }
+
+    // Uploaded picture if there's one, otherwise the picture of the current version. Only uploaded pictures have thumbnails
+    pub fn get_current_picture_path(&self, map : &str, size : Option<u32>) -> Option<PathBuf>
+    {
+        let uploaded_path = self.get_uploaded_picture_path(map, size);
+        if uploaded_path.is_file()
+        {
+            return Some(uploaded_path);
+        }
+
+        let map_entry = self.get(map).ok()?;
+        Some(self.get_picture_path(map, &map_entry.info.map_version)).filter(|_| size.is_none())
+            .filter(|x| x.is_file())
+    }
+
+    // File loaded by the game server
+    pub fn get_map_file_path(&self, map : &str, version : &str) -> PathBuf
+    {
+        self.get_version_folder(map, version).join(map.to_string() + ".bbm")
+    }
+
+    // Folder holding the chunks of an upload until it's committed
+    pub fn get_upload_folder(&self, upload_id : &uuid::Uuid) -> PathBuf
+    {
+        self.maps_folder.join(UPLOADS_FOLDER).join(upload_id.to_string())
+    }
+
+    fn get_config_path(&self, map : &str) -> PathBuf
+    {
+        self.get_map_folder(map).join(map.to_string() + ".yml")
+    }
+
+    pub fn get(&self, map : &str) -> Result<MapEntry, Error>
+    {
+        self.lock().get(map).cloned()
+            .unwrap_or_else(|| Err(Error::new(ErrorCode::MapNotFound, format!("Could not find map with name {}", map))))
+    }
+
+    // Maps which loaded correctly, sorted by name
+    pub fn get_all(&self) -> Vec<MapEntry>
+    {
+        let mut maps : Vec<MapEntry> = self.lock().values().filter_map(|x| x.clone().ok()).collect();
+        maps.sort_by(|a, b| a.info.map_name.cmp(&b.info.map_name));
+        maps
+    }
+
+    // Every map in the catalog, including the ones which failed to load, sorted by name
+    pub fn get_all_results(&self) -> Vec<(String, Result<MapEntry, Error>)>
+    {
+        let mut maps : Vec<(String, Result<MapEntry, Error>)> = self.lock().iter().map(|(name, entry)| (name.clone(), entry.clone())).collect();
+        maps.sort_by(|a, b| a.0.cmp(&b.0));
+        maps
+    }
+
+    // Bytes taken by the map folder, every version included
+    pub fn get_disk_usage(&self, map : &str) -> u64
+    {
+        walkdir::WalkDir::new(self.get_map_folder(map)).into_iter()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| r.metadata().ok())
+            .filter(|x| x.is_file())
+            .map(|x| x.len())
+            .sum()
+    }
+
+    // Held by games while they pick their map, and while a map is checked to be unused and then removed or renamed,
+    // so a game can't pick a map which is about to go away
+    pub fn lock_use(&self) -> std::sync::MutexGuard<'_, ()>
+    {
+        self.use_lock.lock().expect("Error on locking")
+    }
+
+    // Reads the map files again. Maps whose folder is gone are removed from the catalog
+    pub fn reload_map(&self, map : &str)
+    {
+        let _guard = self.reload_lock.lock().expect("Error on locking");
+        if !self.get_map_folder(map).is_dir()
+        {
+            self.lock().remove(map);
+            return;
+        }
+
+        let entry = self.migrate_legacy_password(map)
+            .and_then(|_| self.migrate_legacy_map(map))
+            .and_then(|_| self.read_map_entry(map));
+        let previous = self.lock().insert(map.to_string(), entry.clone());
+        if let Err(err) = &entry
+        {
+            // Files are changed a few times while a map is being written, don't log the same error for each one
+            let repeated = matches!(previous, Some(Err(prev)) if prev.message == err.message);
+            if !repeated
+            {
+                println!("Could not load map {}: {}", map, err.message);
+            }
+        }
+    }
+
+    // Reloads maps as their files change on disk. The catalog stops updating once the watcher is dropped
+    pub fn watch(&self) -> notify::Result<notify::RecommendedWatcher>
+    {
+        let maps_folder = self.maps_folder.canonicalize()?;
+        let catalog = self.clone();
+        let root = maps_folder.clone();
+        let mut watcher = notify::recommended_watcher(move |res : notify::Result<notify::Event>| {
+            match res
+            {
+                Ok(event) if !event.kind.is_access() => {
+                    let mut maps : Vec<String> = event.paths.iter().filter_map(|path| get_map_name(&root, path)).collect();
+                    maps.dedup();
+                    maps.iter().for_each(|map| catalog.reload_map(map));
+                },
+                Ok(_) => {},
+                Err(err) => println!("Error while watching maps folder: {}", err),
+            }
+        })?;
+        watcher.watch(&maps_folder, RecursiveMode::Recursive)?;
+
+        Ok(watcher)
+    }
+
+    // Stores the archive, which must have been validated first, as a new version and makes it the current one.
+    // Everything is written to a temp folder first, so a failed upload leaves the map untouched
+    pub fn install_map<R : Read + Seek>(&self, map : &str, version : &str, archive : &mut ZipArchive<R>, zip_data : &[u8], 
+        metadata : &MapMetadata, access : &MapAccess) -> Result<(), Error>
+    {
+        let tmp_folder = self.maps_folder.join(format!(".upload-{}", uuid::Uuid::new_v4()));
+        let res = self.install_map_from(map, version, archive, zip_data, metadata, access, &tmp_folder);
+        if let Err(err) = std::fs::remove_dir_all(&tmp_folder)
+        {
+            println!("Could not remove temp folder {}: {}", tmp_folder.display(), err);
+        }
+
+        res
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn install_map_from<R : Read + Seek>(&self, map : &str, version : &str, archive : &mut ZipArchive<R>, zip_data : &[u8], 
+        metadata : &MapMetadata, access : &MapAccess, tmp_folder : &Path) -> Result<(), Error>
+    {
+        let version_folder = self.get_version_folder(map, version);
+        if version_folder.exists()
+        {
+            return Err(version_exists_error(map, version));
+        }
+
+        std::fs::create_dir(tmp_folder).map_err(|err| Error::internal("Could not create temp folder", err))?;
+        extract_map_archive(archive, tmp_folder)?;
+
+        let new_version_folder = tmp_folder.join(map);
+        std::fs::write(new_version_folder.join(map.to_string() + ".zip"), zip_data).map_err(|err| Error::internal("Could not write map zip", err))?;
+        write_version_config(&new_version_folder, map, metadata, get_unix_time_ms())?;
+
+        let versions_folder = self.get_map_folder(map).join(VERSIONS_FOLDER);
+        std::fs::create_dir_all(&versions_folder).map_err(|err| Error::internal("Could not create versions folder", err))?;
+        std::fs::rename(&new_version_folder, &version_folder).map_err(|err| Error::internal("Could not move map version", err))?;
+
+        let config_path = self.get_config_path(map);
+        if config_path.is_file()
+        {
+            return self.update_map_config(map, |hash| {
+                hash.insert(Yaml::String("version".to_string()), Yaml::String(version.to_string()));
+            });
+        }
+        write_map_config(&config_path, access, version, 0)
+    }
+
+    // Pictures are decoded and encoded again as JPEG, so anything else in the file is dropped.
+    // Each file is replaced on its own, the thumbnails first
+    pub fn install_picture(&self, map : &str, data : &[u8]) -> Result<(), Error>
+    {
+        let picture = decode_picture(data)?;
+        let mut files = Vec::new();
+        for size in THUMBNAIL_SIZES
+        {
+            let thumbnail = if picture.width() <= size && picture.height() <= size { picture.clone() } else { picture.thumbnail(size, size) };
+            files.push((self.get_uploaded_picture_path(map, Some(size)), encode_picture(&thumbnail)?));
+        }
+        files.push((self.get_uploaded_picture_path(map, None), encode_picture(&picture)?));
+
+        let pictures_folder = self.get_map_folder(map).join(PICTURES_FOLDER);
+        std::fs::create_dir_all(&pictures_folder).map_err(|err| Error::internal("Could not create pictures folder", err))?;
+        for (path, buffer) in files
+        {
+            let tmp_path = path.with_extension("jpg.tmp");
+            std::fs::write(&tmp_path, buffer).and_then(|_| std::fs::rename(&tmp_path, &path))
+                .map_err(|err| Error::internal("Could not write map picture", err))?;
+        }
+
+        self.reload_map(map);
+        Ok(())
+    }
+
+    // Makes a stored version the one used by new games
+    pub fn set_current_version(&self, map : &str, version : &str) -> Result<(), Error>
+    {
+        let map_entry = self.get(map)?;
+        if map_entry.get_version(version).is_none()
+        {
+            return Err(Error::new(ErrorCode::MapNotFound, format!("Could not find version {} of map {}", version, map)));
+        }
+
+        self.update_map_config(map, |hash| {
+            hash.insert(Yaml::String("version".to_string()), Yaml::String(version.to_string()));
+        })?;
+        self.reload_map(map);
+        Ok(())
+    }
+
+    // Called when a game is launched with the map. Used to sort maps by popularity
+    pub fn add_play(&self, map : &str)
+    {
+        let res = self.update_map_config(map, |hash| {
+            let key = Yaml::String("times_played".to_string());
+            let times_played = hash.get(&key).and_then(|x| x.as_i64()).unwrap_or_default();
+            hash.insert(key, Yaml::Integer(times_played + 1));
+        });
+        if let Err(err) = res
+        {
+            println!("Could not update play count of map {}: {}", map, err.message);
+        }
+        self.reload_map(map);
+    }
+
+    // Changes some fields of the config on disk. Every change to an existing config is made under the config lock,
+    // so concurrent ones, like a game launch and an upload, don't overwrite each other
+    fn update_map_config(&self, map : &str, update : impl FnOnce(&mut Hash)) -> Result<(), Error>
+    {
+        let _guard = self.config_lock.lock().expect("Error on locking");
+        let config_path = self.get_config_path(map);
+        let mut hash = read_yaml(&config_path, map)?.into_hash()
+            .ok_or_else(|| Error::new(ErrorCode::InvalidMap, format!("Config file of map {} is invalid", map)))?;
+        update(&mut hash);
+        write_yaml(&config_path, Yaml::Hash(hash))
+    }
+
+    // Removes the oldest versions over the limit. The current version and the ones in use are kept
+    pub fn remove_old_versions(&self, map : &str, in_use : &[String])
+    {
+        let map_entry = match self.get(map)
+        {
+            Ok(map_entry) => map_entry,
+            Err(_) => return,
+        };
+
+        let mut extra = map_entry.versions.len().saturating_sub(self.versions_kept);
+        for version in map_entry.versions.iter().map(|x| &x.version)
+        {
+            if extra == 0
+            {
+                break;
+            }
+            if *version == map_entry.info.map_version || in_use.contains(version)
+            {
+                continue;
+            }
+
+            if let Err(err) = std::fs::remove_dir_all(self.get_version_folder(map, version))
+            {
+                println!("Could not remove version {} of map {}: {}", version, map, err);
+            }
+            extra -= 1;
+        }
+        self.reload_map(map);
+    }
+
+    // Removes the map and every stored version. The folder is moved out of the way first, so the map is either
+    // still whole or gone, even if removing its files fails halfway
+    pub fn remove_map(&self, map : &str) -> Result<(), Error>
+    {
+        let removed_folder = self.maps_folder.join(format!(".removed-{}", uuid::Uuid::new_v4()));
+        std::fs::rename(self.get_map_folder(map), &removed_folder).map_err(|err| Error::internal("Could not remove map folder", err))?;
+        self.reload_map(map);
+        remove_folder(&removed_folder);
+        Ok(())
+    }
+
+    // Files named after the map are renamed, including the ones inside the zips, which are rewritten.
+    // The renamed map is built in a temp folder first, so a failed rename leaves the map untouched
+    pub fn rename_map(&self, map : &str, new_name : &str) -> Result<(), Error>
+    {
+        // The config is copied, so it can't change until the old one is gone
+        let _guard = self.config_lock.lock().expect("Error on locking");
+        let tmp_folder = self.maps_folder.join(format!(".rename-{}", uuid::Uuid::new_v4()));
+        let res = self.rename_map_to(map, new_name, &tmp_folder);
+        if tmp_folder.exists()
+        {
+            remove_folder(&tmp_folder);
+        }
+        res?;
+
+        // The old folder is moved out of the way before removing it. If that fails, the renamed copy is dropped instead,
+        // so there's never more than one copy of the map
+        let removed_folder = self.maps_folder.join(format!(".removed-{}", uuid::Uuid::new_v4()));
+        if let Err(err) = std::fs::rename(self.get_map_folder(map), &removed_folder)
+        {
+            remove_folder(&self.get_map_folder(new_name));
+            return Err(Error::internal("Could not remove folder of renamed map", err));
+        }
+        self.reload_map(map);
+        self.reload_map(new_name);
+        remove_folder(&removed_folder);
+        Ok(())
+    }
+
+    fn rename_map_to(&self, map : &str, new_name : &str, tmp_folder : &Path) -> Result<(), Error>
+    {
+        let map_entry = self.get(map)?;
+        let new_map_folder = self.get_map_folder(new_name);
+        if new_map_folder.exists()
+        {
+            return Err(Error::new(ErrorCode::MapExists, format!("Map {} already exists", new_name)));
+        }
+
+        // Broken versions are left behind, they can't be used anyway
+        for map_version in &map_entry.versions
+        {
+            let version_folder = self.get_version_folder(map, &map_version.version);
+            let new_version_folder = tmp_folder.join(VERSIONS_FOLDER).join(&map_version.version);
+            copy_map_files(&version_folder, &new_version_folder, map, new_name)?;
+
+            let zip_path = self.get_zip_path(map, &map_version.version);
+            if zip_path.is_file()
+            {
+                rename_map_zip(&zip_path, &new_version_folder.join(new_name.to_string() + ".zip"), map, new_name)?;
+            }
+            write_version_config(&new_version_folder, new_name, &map_version.metadata, map_version.uploaded_at)?;
+        }
+        let pictures_folder = self.get_map_folder(map).join(PICTURES_FOLDER);
+        if pictures_folder.is_dir()
+        {
+            copy_map_files(&pictures_folder, &tmp_folder.join(PICTURES_FOLDER), map, new_name)?;
+        }
+        let config_path = tmp_folder.join(new_name.to_string() + ".yml");
+        std::fs::copy(self.get_config_path(map), &config_path).map_err(|err| Error::internal("Could not copy map config", err))?;
+
+        std::fs::rename(tmp_folder, &new_map_folder).map_err(|err| Error::internal("Could not move renamed map", err))
+    }
+
+    // Maps uploaded before versions were stored have their files next to the config, and the zip in the maps folder.
+    // They're moved into a version folder
+    fn migrate_legacy_map(&self, map : &str) -> Result<(), Error>
+    {
+        let map_folder = self.get_map_folder(map);
+        if map_folder.join(VERSIONS_FOLDER).exists() || !map_folder.join(map.to_string() + ".bbm").is_file()
+        {
+            return Ok(());
+        }
+
+        let yml = read_yaml(&self.get_config_path(map), map)?;
+        let version = get_yaml_version(&yml, map)?;
+        if !is_map_version_valid(&version)
+        {
+            return Err(Error::new(ErrorCode::InvalidMap, format!("Version {} of map {} has illegal characters", version, map)));
+        }
+        let metadata = MapMetadata{supported_gamemodes : get_yaml_gamemodes(&yml, map)?, ..Default::default()};
+        let access = get_yaml_access(&yml);
+
+        let version_folder = self.get_version_folder(map, &version);
+        std::fs::create_dir_all(&version_folder).map_err(|err| Error::internal("Could not create version folder", err))?;
+        let config_name = map.to_string() + ".yml";
+        let files = map_folder.read_dir().map_err(|err| Error::internal("Could not read map folder", err))?;
+        for file in files.filter_map(|r| r.ok())
+        {
+            let name = file.file_name();
+            if name == VERSIONS_FOLDER || name.to_str() == Some(config_name.as_str())
+            {
+                continue;
+            }
+            std::fs::rename(file.path(), version_folder.join(name)).map_err(|err| Error::internal("Could not move map file", err))?;
+        }
+
+        let legacy_zip_path = self.maps_folder.join(map.to_string() + ".zip");
+        if legacy_zip_path.is_file()
+        {
+            std::fs::rename(&legacy_zip_path, self.get_zip_path(map, &version)).map_err(|err| Error::internal("Could not move map zip", err))?;
+        }
+
+        write_version_config(&version_folder, map, &metadata, get_unix_time_ms())?;
+        write_map_config(&self.get_config_path(map), &access, &version, 0)?;
+        println!("Moved map {} to version folder {}", map, version);
+        Ok(())
+    }
+
+    // Maps used to store their password as plain text. It's replaced by its hash
+    fn migrate_legacy_password(&self, map : &str) -> Result<(), Error>
+    {
+        let config_path = self.get_config_path(map);
+        if !config_path.is_file()
+        {
+            return Ok(());
+        }
+
+        let yml = read_yaml(&config_path, map)?;
+        let password = match yml["password"].as_str()
+        {
+            Some(password) => password,
+            None => return Ok(()),
+        };
+
+        let mut hash = yml.as_hash().cloned().unwrap_or_default();
+        hash.remove(&Yaml::String("password".to_string()));
+        if !password.is_empty()
+        {
+            hash.insert(Yaml::String("password_hash".to_string()), Yaml::String(hash_password(password)?));
+        }
+        write_yaml(&config_path, Yaml::Hash(hash))?;
+        println!("Hashed password of map {}", map);
+        Ok(())
+    }
+
+    fn read_map_entry(&self, map : &str) -> Result<MapEntry, Error>
+    {
+        let yml = read_yaml(&self.get_config_path(map), map)?;
+        let current_version = get_yaml_version(&yml, map)?;
+        let access = get_yaml_access(&yml);
+        let times_played = yml["times_played"].as_i64().unwrap_or_default() as u64;
+
+        // Broken versions are left out, they can't be used anyway
+        let versions_folder = self.get_map_folder(map).join(VERSIONS_FOLDER);
+        let mut versions : Vec<MapVersion> = versions_folder.read_dir().into_iter().flatten()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| r.file_name().to_str().map(|x| x.to_string()))
+            .filter(|x| is_map_version_valid(x))
+            .filter_map(|version| self.read_map_version(map, &version).ok())
+            .collect();
+        versions.sort_by(|a, b| (a.uploaded_at, &a.version).cmp(&(b.uploaded_at, &b.version)));
+
+        let current = versions.iter().find(|x| x.version == current_version)
+            .ok_or_else(|| Error::new(ErrorCode::InvalidMap, format!("Could not find current version {} of map {}", current_version, map)))?;
+        let uploaded_picture_sha256 = get_file_sha256(&self.get_uploaded_picture_path(map, None));
+        let thumbnail_sizes = THUMBNAIL_SIZES.iter().copied()
+            .filter(|size| uploaded_picture_sha256.is_some() && self.get_uploaded_picture_path(map, Some(*size)).is_file())
+            .collect();
+        let info = MapInfo{
+            map_name : map.to_string(),
+            map_version : current_version.clone(),
+            metadata : current.metadata.clone(),
+            versions : versions.iter().map(|x| x.version.clone()).collect(),
+            zip_sha256 : current.zip_sha256.clone(),
+            picture_sha256 : uploaded_picture_sha256.or_else(|| current.picture_sha256.clone()),
+            thumbnail_sizes,
+            uploaded_at : current.uploaded_at,
+            times_played,
+        };
+        Ok(MapEntry{info, access, versions})
+    }
+
+    fn read_map_version(&self, map : &str, version : &str) -> Result<MapVersion, Error>
+    {
+        let version_folder = self.get_version_folder(map, version);
+        let yml = read_yaml(&version_folder.join(VERSION_CONFIG), map)?;
+        if !self.get_map_file_path(map, version).is_file()
+        {
+            return Err(Error::new(ErrorCode::InvalidMap, format!("Version {} of map {} has no map file", version, map)));
+        }
+
+        // Versions stored before hashes were kept have them computed on every load
+        let zip_path = self.get_zip_path(map, version);
+        let picture_path = self.get_picture_path(map, version);
+        let get_sha256 = |key : &str, path : &Path| yml[key].as_str().map(|x| x.to_string()).filter(|_| path.is_file())
+            .or_else(|| get_file_sha256(path));
+        Ok(MapVersion{
+            version : version.to_string(),
+            metadata : get_yaml_metadata(&yml, map)?,
+            uploaded_at : yml["uploaded_at"].as_i64().unwrap_or_default() as u64,
+            zip_size : std::fs::metadata(&zip_path).ok().map(|x| x.len()),
+            zip_sha256 : get_sha256("zip_sha256", &zip_path),
+            picture_sha256 : get_sha256("picture_sha256", &picture_path),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Result<MapEntry, Error>>>
+    {
+        self.maps.lock().expect("Error on locking")
+    }
+}
+
+fn remove_folder(folder : &Path)
+{
+    if let Err(err) = std::fs::remove_dir_all(folder)
+    {
+        println!("Could not remove folder {}: {}", folder.display(), err);
+    }
+}
+
+fn write_map_config(config_path : &Path, access : &MapAccess, version : &str, times_played : u64) -> Result<(), Error>
+{
+    let mut hash = Hash::new();
+    if !access.password_hash.is_empty()
+    {
+        hash.insert(Yaml::String("password_hash".to_string()), Yaml::String(access.password_hash.clone()));
+    }
+    hash.insert(Yaml::String("version".to_string()), Yaml::String(version.to_string()));
+    hash.insert(Yaml::String("times_played".to_string()), Yaml::Integer(times_played as i64));
+    write_yaml(config_path, Yaml::Hash(hash))
+}
+
+// Versions don't change once stored, so the hashes of the zip and picture are computed only once, here
+fn write_version_config(version_folder : &Path, map : &str, metadata : &MapMetadata, uploaded_at : u64) -> Result<(), Error>
+{
+    let mut hash = Hash::new();
+    let to_yaml = |values : &[String]| Yaml::Array(values.iter().map(|x| Yaml::String(x.to_string())).collect());
+    hash.insert(Yaml::String("gamemodes".to_string()), to_yaml(&metadata.supported_gamemodes));
+    hash.insert(Yaml::String("author".to_string()), Yaml::String(metadata.author.clone()));
+    hash.insert(Yaml::String("description".to_string()), Yaml::String(metadata.description.clone()));
+    hash.insert(Yaml::String("tags".to_string()), to_yaml(&metadata.tags));
+    for (key, players) in [("min_players", metadata.min_players), ("max_players", metadata.max_players)]
+    {
+        if let Some(players) = players
+        {
+            hash.insert(Yaml::String(key.to_string()), Yaml::Integer(players as i64));
+        }
+    }
+    hash.insert(Yaml::String("uploaded_at".to_string()), Yaml::Integer(uploaded_at as i64));
+    for (key, extension) in [("zip_sha256", ".zip"), ("picture_sha256", ".jpg")]
+    {
+        if let Some(sha256) = get_file_sha256(&version_folder.join(map.to_string() + extension))
+        {
+            hash.insert(Yaml::String(key.to_string()), Yaml::String(sha256));
+        }
+    }
+    write_yaml(&version_folder.join(VERSION_CONFIG), Yaml::Hash(hash))
+}
+
+// Written under a temp name first, so readers never see half a file
+fn write_yaml(path : &Path, yml : Yaml) -> Result<(), Error>
+{
+    let mut output = String::new();
+    YamlEmitter::new(&mut output).dump(&yml).map_err(|err| Error::internal("Could not create map config", err))?;
+
+    let tmp_path = path.with_extension("yml.tmp");
+    std::fs::write(&tmp_path, output).and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|err| Error::internal("Could not write map config", err))
+}
+
+fn read_yaml(path : &Path, map : &str) -> Result<Yaml, Error>
+{
+    let data_str = std::fs::read_to_string(path)
+        .map_err(|_| Error::new(ErrorCode::InvalidMap, format!("Could not find config file {} of map {}", get_file_name(path), map)))?;
+    let mut docs = YamlLoader::load_from_str(&data_str)
+        .map_err(|err| Error::new(ErrorCode::InvalidMap, format!("Config file {} of map {} is invalid: {}", get_file_name(path), map, err)))?;
+    if docs.is_empty()
+    {
+        return Err(Error::new(ErrorCode::InvalidMap, format!("Config file {} of map {} is empty", get_file_name(path), map)));
+    }
+
+    Ok(docs.remove(0))
+}
+
+fn get_file_sha256(path : &Path) -> Option<String>
+{
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn get_file_name(path : &Path) -> String
+{
+    path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn get_yaml_version(yml : &Yaml, map : &str) -> Result<String, Error>
+{
+    yml["version"].as_str().map(|x| x.to_string())
+        .ok_or_else(|| Error::new(ErrorCode::InvalidMap, format!("Map {} has no version", map)))
+}
+
+fn get_yaml_access(yml : &Yaml) -> MapAccess
+{
+    MapAccess{
+        password_hash : yml["password_hash"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn hash_password(password : &str) -> Result<String, Error>
+{
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|err| Error::internal("Could not create salt", err))?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|err| Error::internal("Could not hash password", err))?;
+    Ok(hash.to_string())
+}
+
+fn get_yaml_gamemodes(yml : &Yaml, map : &str) -> Result<Vec<String>, Error>
+{
+    let gamemodes = yml["gamemodes"].as_vec()
+        .ok_or_else(|| Error::new(ErrorCode::InvalidMap, format!("Map {} has no gamemodes", map)))?;
+    Ok(gamemodes.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect())
+}
+
+// Everything but the gamemodes is optional, maps uploaded before it was stored have none
+fn get_yaml_metadata(yml : &Yaml, map : &str) -> Result<MapMetadata, Error>
+{
+    let get_strings = |key : &str| yml[key].as_vec().map(|x| x.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect());
+    let get_players = |key : &str| yml[key].as_i64().and_then(|x| u8::try_from(x).ok());
+    Ok(MapMetadata{
+        supported_gamemodes : get_yaml_gamemodes(yml, map)?,
+        author : yml["author"].as_str().unwrap_or_default().to_string(),
+        description : yml["description"].as_str().unwrap_or_default().to_string(),
+        tags : get_strings("tags").unwrap_or_default(),
+        min_players : get_players("min_players"),
+        max_players : get_players("max_players"),
+    })
+}
+
+pub fn version_exists_error(map : &str, version : &str) -> Error
+{
+    Error::new(ErrorCode::MapVersionExists, format!("Version {} of map {} was already uploaded", version, map))
+}
+
+pub fn is_map_name_valid(map_name : &str) -> bool
+{
+    static REG : OnceLock<Regex> = OnceLock::new();
+    REG.get_or_init(|| Regex::new(r"^[A-Za-z0-9_-]{1,32}$").expect("Map name regex is valid")).is_match(map_name)
+}
+
+// Versions are used as folder names, so they can't start with a dot
+pub fn is_map_version_valid(version : &str) -> bool
+{
+    static REG : OnceLock<Regex> = OnceLock::new();
+    REG.get_or_init(|| Regex::new(r"^[A-Za-z0-9_-][A-Za-z0-9._-]{0,31}$").expect("Map version regex is valid")).is_match(version)
+}
+
+// Tags are stored in lowercase, so searches can match them exactly
+pub fn validate_map_metadata(metadata : &mut MapMetadata) -> Result<(), Error>
+{
+    metadata.author = metadata.author.trim().to_string();
+    metadata.description = metadata.description.trim().to_string();
+    metadata.tags = metadata.tags.iter().map(|x| x.trim().to_lowercase()).collect();
+    metadata.tags.sort();
+    metadata.tags.dedup();
+
+    let invalid = |msg : String| Err(Error::new(ErrorCode::InvalidMapMetadata, msg));
+    if metadata.author.chars().count() > MAX_AUTHOR_LEN
+    {
+        return invalid(format!("Author can have up to {} characters", MAX_AUTHOR_LEN));
+    }
+    if metadata.description.chars().count() > MAX_DESCRIPTION_LEN
+    {
+        return invalid(format!("Description can have up to {} characters", MAX_DESCRIPTION_LEN));
+    }
+    if metadata.tags.len() > MAX_TAGS
+    {
+        return invalid(format!("Maps can have up to {} tags", MAX_TAGS));
+    }
+    if let Some(tag) = metadata.tags.iter().find(|x| !is_map_tag_valid(x))
+    {
+        return invalid(format!("Tag {} has illegal characters", tag));
+    }
+    match (metadata.min_players, metadata.max_players)
+    {
+        (Some(0), _) | (_, Some(0)) => invalid("Player amounts must be greater than 0".to_string()),
+        (Some(min), Some(max)) if min > max => invalid(format!("Min players {} is greater than max players {}", min, max)),
+        _ => Ok(()),
+    }
+}
+
+fn is_map_tag_valid(tag : &str) -> bool
+{
+    static REG : OnceLock<Regex> = OnceLock::new();
+    REG.get_or_init(|| Regex::new(r"^[a-z0-9_-]{1,16}$").expect("Map tag regex is valid")).is_match(tag)
+}
+
+// Checks the archive before anything is extracted. Every file must be inside a folder named after the map,
+// which must contain the map file and its picture. The version config and the zip are written by us, so they can't be uploaded
+pub fn validate_map_archive<R : Read + Seek>(map : &str, archive : &mut ZipArchive<R>) -> Result<(), Error>
+{
+    if archive.len() > MAX_MAP_ENTRIES
+    {
+        let err = Error::new(ErrorCode::InvalidMap, format!("Map archive can have up to {} files", MAX_MAP_ENTRIES));
+        return Err(err.with_details(serde_json::json!({"max_entries" : MAX_MAP_ENTRIES})));
+    }
+
+    let map_path = Path::new(map).join(map.to_string() + ".bbm");
+    let picture_path = Path::new(map).join(map.to_string() + ".jpg");
+    let reserved_paths = [Path::new(map).join(VERSION_CONFIG), Path::new(map).join(map.to_string() + ".zip")];
+    let (mut has_map, mut has_picture) = (false, false);
+    let mut total_size : u64 = 0;
+    for i in 0..archive.len()
+    {
+        let file = archive.by_index(i).map_err(|err| invalid_archive(&err.to_string()))?;
+        let path = file.enclosed_name().map(|x| x.to_path_buf())
+            .ok_or_else(|| invalid_archive(&format!("Illegal path {}", file.name())))?;
+        if is_symlink(file.unix_mode())
+        {
+            return Err(invalid_archive(&format!("{} is a symlink", file.name())));
+        }
+        if path.components().next() != Some(Component::Normal(map.as_ref()))
+        {
+            return Err(invalid_archive(&format!("{} is not inside folder {}", file.name(), map)));
+        }
+        if reserved_paths.contains(&path)
+        {
+            return Err(invalid_archive(&format!("{} can't be uploaded", file.name())));
+        }
+
+        total_size = total_size.saturating_add(file.size());
+        if total_size > MAX_MAP_SIZE
+        {
+            return Err(map_too_large());
+        }
+
+        has_map |= path == map_path;
+        has_picture |= path == picture_path;
+    }
+
+    if !has_map
+    {
+        return Err(invalid_archive(&format!("Missing map file {}", map_path.display())));
+    }
+    if !has_picture
+    {
+        return Err(invalid_archive(&format!("Missing map picture {}", picture_path.display())));
+    }
+
+    Ok(())
+}
+
+// Sizes in the archive can't be trusted, so the amount of bytes written is checked too
+fn extract_map_archive<R : Read + Seek>(archive : &mut ZipArchive<R>, folder : &Path) -> Result<(), Error>
+{
+    let mut remaining = MAX_MAP_SIZE;
+    for i in 0..archive.len()
+    {
+        let mut file = archive.by_index(i).map_err(|err| invalid_archive(&err.to_string()))?;
+        let path = file.enclosed_name().map(|x| x.to_path_buf())
+            .ok_or_else(|| invalid_archive(&format!("Illegal path {}", file.name())))?;
+        let outpath = folder.join(path);
+
+        if file.is_dir()
+        {
+            std::fs::create_dir_all(&outpath).map_err(|err| Error::internal("Could not create map folder", err))?;
+            continue;
+        }
+
+        if let Some(parent) = outpath.parent()
+        {
+            std::fs::create_dir_all(parent).map_err(|err| Error::internal("Could not create map folder", err))?;
+        }
+        let mut outfile = std::fs::File::create(&outpath).map_err(|err| Error::internal("Could not create map file", err))?;
+        let written = std::io::copy(&mut (&mut file).take(remaining + 1), &mut outfile)
+            .map_err(|err| invalid_archive(&err.to_string()))?;
+        if written > remaining
+        {
+            return Err(map_too_large());
+        }
+        remaining -= written;
+    }
+
+    Ok(())
+}
+
+// Copies the extracted files of a version. The zip and the version config are written again by the caller
+fn copy_map_files(version_folder : &Path, new_version_folder : &Path, map : &str, new_name : &str) -> Result<(), Error>
+{
+    let reserved_files = [map.to_string() + ".zip", VERSION_CONFIG.to_string()];
+    for entry in walkdir::WalkDir::new(version_folder)
+    {
+        let entry = entry.map_err(|err| Error::internal("Could not read version folder", err))?;
+        let relative = entry.path().strip_prefix(version_folder).map_err(|err| Error::internal("Could not read version folder", err))?;
+        let relative = relative.to_string_lossy().to_string();
+        if entry.file_type().is_dir()
+        {
+            std::fs::create_dir_all(new_version_folder.join(&relative)).map_err(|err| Error::internal("Could not create version folder", err))?;
+            continue;
+        }
+        if reserved_files.contains(&relative)
+        {
+            continue;
+        }
+
+        let new_path = new_version_folder.join(rename_map_file(&relative, map, new_name));
+        std::fs::copy(entry.path(), new_path).map_err(|err| Error::internal("Could not copy map file", err))?;
+    }
+
+    Ok(())
+}
+
+// Files are copied without being decompressed, only their names change
+fn rename_map_zip(zip_path : &Path, new_zip_path : &Path, map : &str, new_name : &str) -> Result<(), Error>
+{
+    let file = std::fs::File::open(zip_path).map_err(|err| Error::internal("Could not open map zip", err))?;
+    let mut archive = ZipArchive::new(file).map_err(|err| invalid_archive(&err.to_string()))?;
+    let new_file = std::fs::File::create(new_zip_path).map_err(|err| Error::internal("Could not create map zip", err))?;
+    let mut writer = zip::ZipWriter::new(new_file);
+
+    let prefix = map.to_string() + "/";
+    for i in 0..archive.len()
+    {
+        let file = archive.by_index_raw(i).map_err(|err| invalid_archive(&err.to_string()))?;
+        let name = match file.name().strip_prefix(&prefix)
+        {
+            Some(relative) => format!("{}/{}", new_name, rename_map_file(relative, map, new_name)),
+            None => file.name().to_string(),
+        };
+        writer.raw_copy_file_rename(file, name).map_err(|err| Error::internal("Could not write map zip", err))?;
+    }
+    writer.finish().map_err(|err| Error::internal("Could not write map zip", err))?;
+
+    Ok(())
+}
+
+// Files at the top of a version folder which are named after the map, like <map>.bbm, take the new name.
+// Paths are relative to the version folder, with / as separator
+fn rename_map_file(relative : &str, map : &str, new_name : &str) -> String
+{
+    match relative.strip_prefix(map)
+    {
+        Some(rest) if !rest.contains('/') && (rest.is_empty() || rest.starts_with('.')) => new_name.to_string() + rest,
+        _ => relative.to_string(),
+    }
+}
+
+// Only JPEG and PNG are accepted. Their size is checked before decoding, so huge pictures are rejected without allocating them
+fn decode_picture(data : &[u8]) -> Result<DynamicImage, Error>
+{
+    if data.len() > MAX_PICTURE_SIZE
+    {
+        let err = Error::new(ErrorCode::PayloadTooLarge, format!("Pictures can have up to {} bytes", MAX_PICTURE_SIZE));
+        return Err(err.with_details(serde_json::json!({"max_size" : MAX_PICTURE_SIZE})));
+    }
+
+    let format = image::guess_format(data).ok().filter(|x| matches!(x, ImageFormat::Jpeg | ImageFormat::Png))
+        .ok_or_else(|| Error::new(ErrorCode::UnsupportedMediaType, "Pictures must be JPEG or PNG"))?;
+    let mut reader = image::io::Reader::with_format(Cursor::new(data), format);
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_PICTURE_DIMENSION);
+    limits.max_image_height = Some(MAX_PICTURE_DIMENSION);
+    reader.limits(limits);
+
+    reader.decode().map_err(|err| match err
+    {
+        ImageError::Limits(_) => {
+            let err = Error::new(ErrorCode::PayloadTooLarge, format!("Pictures can be up to {0}x{0} pixels", MAX_PICTURE_DIMENSION));
+            err.with_details(serde_json::json!({"max_dimension" : MAX_PICTURE_DIMENSION}))
+        },
+        err => Error::new(ErrorCode::InvalidPicture, format!("Picture is invalid: {}", err)),
+    })
+}
+
+// Transparent pixels lose their alpha, JPEG doesn't have it
+fn encode_picture(picture : &DynamicImage) -> Result<Vec<u8>, Error>
+{
+    let mut buffer = Vec::new();
+    JpegEncoder::new_with_quality(&mut buffer, PICTURE_QUALITY).encode_image(&picture.to_rgb8())
+        .map_err(|err| Error::internal("Could not encode map picture", err))?;
+    Ok(buffer)
+}
+
+fn is_symlink(unix_mode : Option<u32>) -> bool
+{
+    const S_IFMT : u32 = 0o170000;
+    const S_IFLNK : u32 = 0o120000;
+    unix_mode.map(|mode| mode & S_IFMT == S_IFLNK).unwrap_or(false)
+}
+
+fn invalid_archive(reason : &str) -> Error
+{
+    Error::new(ErrorCode::InvalidMap, format!("Map archive is invalid: {}", reason))
+}
+
+fn map_too_large() -> Error
+{
+    let err = Error::new(ErrorCode::PayloadTooLarge, format!("Maps can take up to {} bytes once extracted", MAX_MAP_SIZE));
+    err.with_details(serde_json::json!({"max_size" : MAX_MAP_SIZE}))
+}
+
+// Name of the map a file belongs to. Every file of a map is in its folder, with zips in <map>/versions/<version>/,
+// except the zips of maps stored with the older layout, which are next to the map folders as <map>.zip until migrated.
+// Anything else, like the temp folders used by uploads, is ignored
+fn get_map_name(maps_folder : &Path, path : &Path) -> Option<String>
+{
+    let relative = path.strip_prefix(maps_folder).ok()?;
+    let first = Path::new(relative.components().next()?.as_os_str());
+    let name = if relative.components().count() == 1 && first.extension().map(|x| x == "zip").unwrap_or(false)
+    {
+        first.file_stem()?.to_str()?
+    }
+    else
+    {
+        first.to_str()?
+    };
+
+    Some(name.to_string()).filter(|x| is_map_name_valid(x))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    fn build_archive(files : &[(&str, &[u8])]) -> ZipArchive<Cursor<Vec<u8>>>
+    {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files
+        {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        let data = writer.finish().unwrap().into_inner();
+        ZipArchive::new(Cursor::new(data)).unwrap()
+    }
+
+    fn validate(files : &[(&str, &[u8])]) -> Result<(), Error>
+    {
+        validate_map_archive("arena", &mut build_archive(files))
+    }
+
+    #[test]
+    fn accepts_valid_archive()
+    {
+        assert!(validate(&[("arena/arena.bbm", b"map"), ("arena/arena.jpg", b"picture"), ("arena/extra/notes.txt", b"")]).is_ok());
+    }
+
+    #[test]
+    fn requires_map_and_picture()
+    {
+        assert_eq!(validate(&[("arena/arena.jpg", b"picture")]).unwrap_err().code, ErrorCode::InvalidMap);
+        assert_eq!(validate(&[("arena/arena.bbm", b"map")]).unwrap_err().code, ErrorCode::InvalidMap);
+    }
+
+    #[test]
+    fn rejects_path_traversal()
+    {
+        let base : [(&str, &[u8]); 2] = [("arena/arena.bbm", b"map"), ("arena/arena.jpg", b"picture")];
+        for name in ["../arena.bbm", "arena/../../evil.txt", "/etc/evil.txt", "other/file.txt", "arena.bbm"]
+        {
+            let mut files = base.to_vec();
+            files.push((name, b""));
+            assert_eq!(validate(&files).unwrap_err().code, ErrorCode::InvalidMap, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_files()
+    {
+        let config = format!("arena/{}", VERSION_CONFIG);
+        for name in [config.as_str(), "arena/arena.zip"]
+        {
+            let files : [(&str, &[u8]); 3] = [("arena/arena.bbm", b"map"), ("arena/arena.jpg", b"picture"), (name, b"")];
+            assert_eq!(validate(&files).unwrap_err().code, ErrorCode::InvalidMap, "{}", name);
+        }
+    }
+
+    #[test]
+    fn detects_symlinks()
+    {
+        assert!(is_symlink(Some(0o120777)));
+        assert!(!is_symlink(Some(0o100644)));
+        assert!(!is_symlink(Some(0o040755)));
+        assert!(!is_symlink(None));
+    }
+
+    #[test]
+    fn rejects_too_many_entries()
+    {
+        let names : Vec<String> = (0..MAX_MAP_ENTRIES - 1).map(|i| format!("arena/{}.txt", i)).collect();
+        let mut files : Vec<(&str, &[u8])> = vec![("arena/arena.bbm", b"map"), ("arena/arena.jpg", b"picture")];
+        files.extend(names.iter().map(|name| (name.as_str(), &b""[..])));
+        assert_eq!(validate(&files).unwrap_err().code, ErrorCode::InvalidMap);
+
+        files.pop();
+        assert!(validate(&files).is_ok());
+    }
+
+    #[test]
+    fn rejects_oversized_content()
+    {
+        let half = vec![0u8; (MAX_MAP_SIZE / 2) as usize];
+        let files : [(&str, &[u8]); 3] = [("arena/arena.bbm", &half), ("arena/arena.jpg", &half), ("arena/extra.txt", b"x")];
+        assert_eq!(validate(&files).unwrap_err().code, ErrorCode::PayloadTooLarge);
+        assert!(validate(&files[..2]).is_ok());
+    }
+}
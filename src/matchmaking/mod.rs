@@ -2,4 +2,8 @@
 pub mod entity;
 pub mod payload;
 pub mod endpoints;
-pub mod database;
\ No newline at end of file
+pub mod database;
+pub mod ping;
+pub mod modes;
+pub mod ratelimit;
+pub mod maps;
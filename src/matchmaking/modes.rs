@@ -0,0 +1,44 @@
+
+#[derive(Debug)]
+pub struct ModeInfo{
+    pub name : &'static str,
+    pub teams : u8, // 0 if players don't play in teams
+    pub max_team_size : u8,
+    pub min_players : u8, // Required to auto start a game. Also the lowest max players a game can have
+    pub max_players : u8, // Highest max players a game can have
+}
+
+pub const MODES : [ModeInfo; 4] = [
+    ModeInfo{ name : "Free for All", teams : 0, max_team_size : 0, min_players : 2, max_players : 16 },
+    ModeInfo{ name : "Team Deathmatch", teams : 2, max_team_size : 8, min_players : 2, max_players : 16 },
+    ModeInfo{ name : "Domination", teams : 2, max_team_size : 8, min_players : 2, max_players : 16 },
+    ModeInfo{ name : "Capture the Flag", teams : 2, max_team_size : 8, min_players : 2, max_players : 16 },
+];
+
+pub fn get_mode(name : &str) -> Option<&'static ModeInfo>
+{
+    MODES.iter().find(|mode| mode.name.eq_ignore_ascii_case(name))
+}
+
+// Amount of teams for this mode. Unknown modes are played without teams
+pub fn get_team_count(name : &str) -> u8
+{
+    get_mode(name).map(|mode| mode.teams).unwrap_or(0)
+}
+
+pub fn get_min_players(name : &str) -> u8
+{
+    get_mode(name).map(|mode| mode.min_players).unwrap_or(2)
+}
+
+// Max players per team, taking the game's max players into account
+pub fn get_team_cap(name : &str, max_players : u8) -> u8
+{
+    if let Some(mode) = get_mode(name).filter(|mode| mode.teams > 0)
+    {
+        let even_split = max_players.div_ceil(mode.teams);
+        return std::cmp::min(mode.max_team_size, even_split);
+    }
+
+    return 0;
+}
@@ -14,7 +14,10 @@ pub mod request
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct ListGames
     {
-        pub full : bool,
+        #[serde(default)]
+        pub player_id : Option<uuid::Uuid>, // Used to show this player's own ping to each game
+        #[serde(default)]
+        pub region : Option<String>,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -22,6 +25,8 @@ pub mod request
     {
         pub player_id : uuid::Uuid,
         pub game_id : uuid::Uuid,
+        #[serde(default)]
+        pub spectator : bool,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -32,6 +37,17 @@ pub mod request
         pub map : String,
         pub mode : String,
         pub max_players : u8,
+        #[serde(default = "default_max_spectators")]
+        pub max_spectators : u8,
+        #[serde(default)]
+        pub region : Option<String>, // Preferred region. Lowest latency one if not given
+        #[serde(default)]
+        pub auto_start : bool,
+    }
+
+    fn default_max_spectators() -> u8
+    {
+        4
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -42,6 +58,16 @@ pub mod request
         pub name : String,
         pub map : String,
         pub mode : String, 
+        #[serde(default)]
+        pub auto_start : Option<bool>, // Unchanged if not given
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct QuickPlay
+    {
+        pub player_id : uuid::Uuid,
+        #[serde(default)]
+        pub region : Option<String>,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -56,6 +82,61 @@ pub mod request
         pub player_id : uuid::Uuid,
     }
     
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct KickPlayer
+    {
+        pub player_id : uuid::Uuid,
+        pub target_id : uuid::Uuid, // Public id of the target
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct BanPlayer
+    {
+        pub player_id : uuid::Uuid,
+        pub target_id : uuid::Uuid, // Public id of the target
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct MutePlayer
+    {
+        pub player_id : uuid::Uuid,
+        pub target_id : uuid::Uuid, // Public id of the target
+        pub muted : bool,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct TransferHost
+    {
+        pub player_id : uuid::Uuid,
+        pub target_id : uuid::Uuid, // Public id of the new host
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct SelectTeam
+    {
+        pub player_id : uuid::Uuid,
+        pub team : u8,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct LockTeams
+    {
+        pub player_id : uuid::Uuid,
+        pub locked : bool,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ShuffleTeams
+    {
+        pub player_id : uuid::Uuid,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct BalanceTeams
+    {
+        pub player_id : uuid::Uuid,
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct SendChatMsg
     {
@@ -63,6 +144,35 @@ pub mod request
         pub msg : String,
     }
 
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct GetChat
+    {
+        pub player_id : uuid::Uuid,
+        #[serde(default)]
+        pub after_id : u64, // Only messages with a greater id are returned
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct SendChannelMsg
+    {
+        pub player_id : uuid::Uuid,
+        #[serde(default)]
+        pub target_id : Option<uuid::Uuid>, // Public id of the player to send a direct message to. Global channel if not given
+        pub msg : String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct GetChannelMsgs
+    {
+        pub player_id : uuid::Uuid,
+        #[serde(default)]
+        pub target_id : Option<uuid::Uuid>, // Public id of the player whose direct messages are read. Global channel if not given
+        #[serde(default)]
+        pub after_id : u64,
+        #[serde(default)]
+        pub wait : bool, // Wait for new messages if there are none
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct UpdateGame
     {
@@ -78,10 +188,54 @@ pub mod request
         pub player_id : uuid::Uuid
     }
 
+    #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum MapSort
+    {
+        #[default]
+        Name,
+        Newest, // Most recently updated first
+        MostPlayed,
+    }
+
+    // Clients may also send no body at all, to get the first page of every map
+    #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+    pub struct GetAvailableMaps
+    {
+        #[serde(default)]
+        pub search : Option<String>, // Matched against the name, author, description and tags
+        #[serde(default)]
+        pub tags : Vec<String>, // Maps must have every one of them
+        #[serde(default)]
+        pub sort : MapSort,
+        #[serde(default)]
+        pub page : usize, // Starts at 0
+        #[serde(default)]
+        pub page_size : Option<usize>,
+    }
+
+    // Sent along with the map on upload. Stored for each version
+    #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+    pub struct MapMetadata
+    {
+        pub supported_gamemodes : Vec<String>,
+        #[serde(default)]
+        pub author : String,
+        #[serde(default)]
+        pub description : String,
+        #[serde(default)]
+        pub tags : Vec<String>,
+        #[serde(default)]
+        pub min_players : Option<u8>, // Recommended amount of players
+        #[serde(default)]
+        pub max_players : Option<u8>,
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct DownloadMap
     {
         pub map_name : String,
+        #[serde(default)]
+        pub map_version : Option<String>, // Current version if missing
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -90,14 +244,86 @@ pub mod request
         pub map_name : String,
         pub map_zip : String,
         pub password : String, // Used to update the map
-        pub supported_gamemodes : Vec<String>,
         pub map_version : String,
+        #[serde(flatten)]
+        pub metadata : MapMetadata,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct UploadMapPicture
+    {
+        pub map_name : String,
+        pub picture : String, // JPEG or PNG, as base64
+        #[serde(default)]
+        pub password : String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct RollbackMap
+    {
+        pub map_name : String,
+        #[serde(default)]
+        pub password : String,
+        pub map_version : String, // Stored version to go back to
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct DeleteMap
+    {
+        pub map_name : String,
+        #[serde(default)]
+        pub password : String,
+        #[serde(default)]
+        pub admin_key : Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct RenameMap
+    {
+        pub map_name : String,
+        pub new_map_name : String,
+        #[serde(default)]
+        pub password : String,
+        #[serde(default)]
+        pub admin_key : Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct AdminListMaps
+    {
+        pub admin_key : String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct StartMapUpload
+    {
+        pub map_name : String,
+        pub password : String, // Used to update the map
+        pub map_version : String,
+        #[serde(flatten)]
+        pub metadata : MapMetadata,
+        pub size : u64, // Size of the zip file in bytes
+        pub sha256 : String, // Hash of the zip file, as hex
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct UploadMapChunk
+    {
+        pub upload_id : uuid::Uuid,
+        pub index : u64,
+        pub data : String, // Base64
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct MapUpload
+    {
+        pub upload_id : uuid::Uuid,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub enum ServerEvent
     {
-        PlayerLeft{ player_id : uuid::Uuid},
+        PlayerLeft{ player_id : uuid::Uuid}, // Public id, as given to the server in -teams
         GameEnded
     }
 
@@ -114,18 +340,83 @@ pub mod request
     {
         pub map_name : String
     }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct HostPing
+    {
+        pub host_id : uuid::Uuid,
+        pub ping : u16,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ReportPing
+    {
+        pub player_id : uuid::Uuid,
+        pub pings : Vec<HostPing>,
+    }
+
+    // Requests made on behalf of a player, which are also rate limited per player
+    pub trait PlayerRequest
+    {
+        fn get_player_id(&self) -> Option<uuid::Uuid>;
+    }
+
+    macro_rules! impl_player_request {
+        ($($request:ty),*) => {
+            $(impl PlayerRequest for $request
+            {
+                fn get_player_id(&self) -> Option<uuid::Uuid>
+                {
+                    Some(self.player_id)
+                }
+            })*
+        };
+    }
+
+    macro_rules! impl_anonymous_request {
+        ($($request:ty),*) => {
+            $(impl PlayerRequest for $request
+            {
+                fn get_player_id(&self) -> Option<uuid::Uuid>
+                {
+                    None
+                }
+            })*
+        };
+    }
+
+    macro_rules! impl_optional_player_request {
+        ($($request:ty),*) => {
+            $(impl PlayerRequest for $request
+            {
+                fn get_player_id(&self) -> Option<uuid::Uuid>
+                {
+                    self.player_id
+                }
+            })*
+        };
+    }
+
+    impl_player_request!(JoinGame, CreateGame, EditGame, QuickPlay, LeaveGame, ToggleReady, KickPlayer, BanPlayer, MutePlayer,
+        TransferHost, SelectTeam, LockTeams, ShuffleTeams, BalanceTeams, SendChatMsg, GetChat, SendChannelMsg, GetChannelMsgs,
+        StartGame, ReportPing);
+    impl_anonymous_request!(Login, UpdateGame, DownloadMap, UploadMapChunk, MapUpload, NotifyServerEvent, MapPicture, AdminListMaps, 
+        GetAvailableMaps, UploadMap, UploadMapPicture, RollbackMap, DeleteMap, RenameMap, StartMapUpload);
+    impl_optional_player_request!(ListGames);
 }
 
 pub mod response
 {
     use serde::{Deserialize, Serialize};
 
-    use crate::matchmaking::entity::GameState;
+    use crate::matchmaking::entity::{ChatMsg, GameState, StateTransition};
+    use super::request::MapMetadata;
     
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Login
     {
         pub id : uuid::Uuid,
+        pub public_id : uuid::Uuid, // Id other players see
         pub username : String,
     }
 
@@ -135,11 +426,19 @@ pub mod response
         pub name : String,
         pub map : String,
         pub map_version : String,
+        pub map_zip_sha256 : Option<String>, // Hashes of the files of the map version, as lowercase hex
+        pub map_picture_sha256 : Option<String>,
         pub mode : String,
         pub max_players : u8,
         pub players : u8,
+        pub max_spectators : u8,
+        pub spectators : u8,
         pub ping : u16,
-        pub chat : Vec<String>,
+        pub region : String,
+        pub chat : Vec<ChatMsg>,
+        pub teams : u8,
+        pub teams_locked : bool,
+        pub auto_start : bool,
 
         pub address : Option<String>,
         pub port : Option<u16>,
@@ -151,11 +450,20 @@ pub mod response
         pub games : Vec<GameInfo>,
     }
 
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct Chat{
+        pub msgs : Vec<ChatMsg>,
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct PlayerInfo{
+        pub id : uuid::Uuid, // Public id
         pub name : String,
         pub ready : bool,
-        pub host : bool
+        pub host : bool,
+        pub spectator : bool,
+        pub team : Option<u8>,
+        pub muted : bool,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -163,6 +471,7 @@ pub mod response
     {
         pub game_info : GameInfo,
         pub players : Vec<PlayerInfo>,
+        pub state_history : Vec<StateTransition>,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -170,7 +479,14 @@ pub mod response
     {
         pub map_name : String,
         pub map_version : String,
-        pub supported_gamemodes : Vec<String>,
+        #[serde(flatten)]
+        pub metadata : MapMetadata, // Of the current version
+        pub versions : Vec<String>, // Stored versions, oldest first
+        pub zip_sha256 : Option<String>, // Hashes of the current version files, as lowercase hex
+        pub picture_sha256 : Option<String>, // Of the uploaded picture instead, if the map has one
+        pub thumbnail_sizes : Vec<u32>, // Sizes served as /maps/<map>/pictures/picture_<size>.jpg. Empty without an uploaded picture
+        pub uploaded_at : u64, // Unix time in ms of the current version
+        pub times_played : u64, // Games launched with the map
         // pub map_picture : String
     }
 
@@ -178,17 +494,157 @@ pub mod response
     pub struct AvailableMaps
     {
         pub maps : Vec<MapInfo>,
+        pub total : usize, // Maps which matched the search, in every page
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct AdminMapInfo
+    {
+        pub map_name : String,
+        pub map_version : Option<String>, // None if the map failed to load
+        pub versions : Vec<String>,
+        pub has_password : bool,
+        pub disk_usage : u64, // Bytes, every version included
+        pub games : usize, // Games using the map which haven't been closed
+        pub error : Option<String>, // Why the map failed to load
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct AdminMapList
+    {
+        pub maps : Vec<AdminMapInfo>,
+        pub disk_usage : u64,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct DownloadMap
     {
         pub map : String,
+        pub sha256 : Option<String>, // Hash of the zip, to check the download
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct MapPicture
     {
         pub map_picture : String,
+        pub sha256 : Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct MapUpload
+    {
+        pub upload_id : uuid::Uuid,
+        pub chunk_size : u64, // Every chunk but the last one must have this size
+        pub chunk_count : u64,
+        pub missing_chunks : Vec<u64>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct HostInfo
+    {
+        pub id : uuid::Uuid,
+        pub name : String,
+        pub region : String,
+        pub address : String,
+        pub ping_port : u16,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ListHosts
+    {
+        pub hosts : Vec<HostInfo>,
+    }
+
+    // Stable identifiers of every error. Clients should rely on these rather than on the messages
+    #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorCode
+    {
+        // Malformed or invalid requests
+        InvalidRequest,
+        InvalidMapName,
+        InvalidMapVersion,
+        InvalidMapMetadata,
+        InvalidGameName,
+        InvalidGameMode, // Not in the modes registry
+        ModeNotSupported, // By the game's map
+        InvalidMaxPlayers,
+        InvalidMaxSpectators,
+        InvalidTeam,
+        InvalidTarget,
+        EmptyChatMsg,
+
+        // Not allowed
+        NotHost,
+        WrongPassword,
+        WrongServerKey,
+        NotAdmin,
+        PlayerBanned,
+        PlayerMuted,
+        TeamsLocked,
+
+        // Missing entities
+        PlayerNotFound,
+        PlayerNotInGame,
+        GameNotFound,
+        MapNotFound,
+        MapPictureNotFound,
+        UploadNotFound,
+        HostNotFound,
+        NoGameAvailable,
+        RouteNotFound,
+
+        MethodNotAllowed,
+
+        InvalidMap, // Map files are missing or malformed
+        InvalidPicture,
+        HashMismatch,
+
+        // Conflicts with the current state
+        GameFull,
+        TeamFull,
+        InvalidGameState,
+        UploadIncomplete,
+        MapVersionExists,
+        MapExists,
+        MapInUse,
+
+        PayloadTooLarge,
+        ChatMsgTooLong,
+        UnsupportedMediaType,
+        RateLimited,
+        TooManyUploads, // Open uploads of the client, or of every client, are over the limit
+
+        LaunchFailed,
+        Internal,
+    }
+
+    // Body of every error reply
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct Error
+    {
+        pub code : ErrorCode,
+        pub message : String,
+        pub details : Option<serde_json::Value>, // Extra info for some codes, like the time to wait when rate limited
+    }
+
+    impl Error
+    {
+        pub fn new(code : ErrorCode, message : impl Into<String>) -> Error
+        {
+            Error{code, message : message.into(), details : None}
+        }
+
+        // Logs the cause of an error the client can't do anything about
+        pub fn internal(context : &str, err : impl std::fmt::Display) -> Error
+        {
+            println!("{}: {}", context, err);
+            Error::new(ErrorCode::Internal, context)
+        }
+
+        pub fn with_details(mut self, details : serde_json::Value) -> Error
+        {
+            self.details = Some(details);
+            self
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+// Max size of an echo datagram. Clients only need to send a sequence number or a timestamp
+const MAX_ECHO_SIZE : usize = 64;
+
+// UDP echo service. Clients send a small datagram and measure the time it takes to come back,
+// which gives them their RTT to the machine hosting the game servers.
+pub async fn run_echo_service(address : SocketAddr) -> std::io::Result<()>
+{
+    let socket = UdpSocket::bind(address).await?;
+    println!("Ping echo service listening on {}", address);
+
+    let mut buffer = [0u8; MAX_ECHO_SIZE];
+    loop
+    {
+        // Errors only affect a single datagram. They aren't logged, as any client could flood the log with them
+        if let Ok((len, from)) = socket.recv_from(&mut buffer).await
+        {
+            let _ = socket.send_to(&buffer[..len], from).await;
+        }
+    }
+}
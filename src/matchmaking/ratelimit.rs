@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Allows bursts of up to capacity actions, then one action every 1 / refill_rate seconds
+#[derive(Debug, Clone)]
+pub struct TokenBucket{
+    capacity : f32,
+    tokens : f32,
+    refill_rate : f32, // Tokens per second
+    last_refill : Instant,
+}
+
+impl TokenBucket{
+
+    pub fn new(capacity : f32, refill_rate : f32) -> TokenBucket{
+        TokenBucket{
+            capacity,
+            tokens : capacity,
+            refill_rate,
+            last_refill : Instant::now(),
+        }
+    }
+
+    // Takes a token if there's one available. Otherwise returns the time until the next one
+    pub fn try_take(&mut self) -> Result<(), Duration>
+    {
+        self.refill();
+        if self.tokens >= 1.0
+        {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let missing = 1.0 - self.tokens;
+        Err(Duration::from_secs_f32(missing / self.refill_rate))
+    }
+
+    fn refill(&mut self)
+    {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // A full bucket behaves like a new one, so it can be dropped
+    pub fn is_full(&mut self) -> bool
+    {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit{
+    pub capacity : f32,
+    pub refill_rate : f32, // Requests per second
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RouteLimits{
+    pub per_ip : Limit,
+    pub per_player : Limit,
+}
+
+// Limits applied to each route. Routes without an entry use the default ones
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig{
+    pub default : RouteLimits,
+    pub routes : HashMap<String, RouteLimits>,
+}
+
+impl Default for RateLimitConfig{
+
+    fn default() -> Self{
+        let limits = |ip : (f32, f32), player : (f32, f32)| RouteLimits{
+            per_ip : Limit{capacity : ip.0, refill_rate : ip.1},
+            per_player : Limit{capacity : player.0, refill_rate : player.1},
+        };
+
+        let mut routes = HashMap::new();
+        routes.insert("login".to_string(), limits((5.0, 0.1), (5.0, 0.1)));
+        routes.insert("create_game".to_string(), limits((5.0, 0.2), (3.0, 0.1)));
+        routes.insert("upload_map".to_string(), limits((3.0, 1.0 / 30.0), (3.0, 1.0 / 30.0)));
+        routes.insert("upload_map_picture".to_string(), limits((5.0, 0.1), (5.0, 0.1)));
+        routes.insert("start_map_upload".to_string(), limits((3.0, 1.0 / 30.0), (3.0, 1.0 / 30.0)));
+        routes.insert("delete_map".to_string(), limits((5.0, 0.1), (5.0, 0.1)));
+        routes.insert("rename_map".to_string(), limits((5.0, 0.1), (5.0, 0.1)));
+        routes.insert("admin_list_maps".to_string(), limits((5.0, 0.5), (5.0, 0.5)));
+        // Not a route. Applies to the messages a player sends to any chat
+        routes.insert("chat_msg".to_string(), limits((5.0, 0.5), (5.0, 0.5)));
+        RateLimitConfig{
+            default : limits((40.0, 20.0), (20.0, 10.0)),
+            routes,
+        }
+    }
+}
+
+impl RateLimitConfig{
+
+    // YAML map of route name => per_ip and/or per_player limits, each one with a capacity and a refill_rate.
+    // The "default" entry applies to routes which aren't listed
+    pub fn from_yaml(data_str : &str) -> Result<RateLimitConfig, String>
+    {
+        let docs = yaml_rust::YamlLoader::load_from_str(data_str).map_err(|err| err.to_string())?;
+        let entries = docs.first().and_then(|doc| doc.as_hash()).ok_or("Expected a map of routes")?;
+
+        let mut config = RateLimitConfig::default();
+        for (route, entry) in entries
+        {
+            let route = route.as_str().ok_or("Route names must be strings")?;
+            let mut limits = config.get_limits(route);
+            if !entry["per_ip"].is_badvalue()
+            {
+                limits.per_ip = read_limit(&entry["per_ip"]).ok_or(format!("Invalid per_ip limit for {}", route))?;
+            }
+            if !entry["per_player"].is_badvalue()
+            {
+                limits.per_player = read_limit(&entry["per_player"]).ok_or(format!("Invalid per_player limit for {}", route))?;
+            }
+
+            if route == "default"
+            {
+                config.default = limits;
+            }
+            else
+            {
+                config.routes.insert(route.to_string(), limits);
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn get_limits(&self, route : &str) -> RouteLimits
+    {
+        *self.routes.get(route).unwrap_or(&self.default)
+    }
+}
+
+fn read_limit(entry : &yaml_rust::Yaml) -> Option<Limit>
+{
+    let number = |name : &str| entry[name].as_f64().or_else(|| entry[name].as_i64().map(|x| x as f64));
+    let limit = Limit{capacity : number("capacity")? as f32, refill_rate : number("refill_rate")? as f32};
+    if limit.capacity < 1.0 || limit.refill_rate <= 0.0
+    {
+        return None;
+    }
+
+    Some(limit)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client{
+    Ip(IpAddr),
+    Player(uuid::Uuid),
+}
+
+// Request was over the limit. Clients should wait retry_after before trying again
+#[derive(Debug)]
+pub struct RateLimited{
+    pub retry_after : Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+#[derive(Clone)]
+pub struct RateLimiter{
+    config : Arc<RateLimitConfig>,
+    buckets : Arc<Mutex<HashMap<(&'static str, Client), TokenBucket>>>,
+}
+
+impl RateLimiter{
+
+    pub fn new(config : RateLimitConfig) -> RateLimiter{
+        RateLimiter{
+            config : Arc::new(config),
+            buckets : Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn check_ip(&self, route : &'static str, ip : IpAddr) -> Result<(), RateLimited>
+    {
+        let limit = self.config.get_limits(route).per_ip;
+        self.check(route, Client::Ip(ip), limit)
+    }
+
+    pub fn check_player(&self, route : &'static str, player_id : uuid::Uuid) -> Result<(), RateLimited>
+    {
+        let limit = self.config.get_limits(route).per_player;
+        self.check(route, Client::Player(player_id), limit)
+    }
+
+    // Drops the buckets of clients which haven't made requests lately
+    pub fn remove_idle(&self)
+    {
+        self.buckets.lock().expect("Error on locking").retain(|_, bucket| !bucket.is_full());
+    }
+
+    fn check(&self, route : &'static str, client : Client, limit : Limit) -> Result<(), RateLimited>
+    {
+        let mut buckets = self.buckets.lock().expect("Error on locking");
+        let bucket = buckets.entry((route, client)).or_insert_with(|| TokenBucket::new(limit.capacity, limit.refill_rate));
+        bucket.try_take().map_err(|retry_after| RateLimited{retry_after})
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Moves the last refill back in time, as if that much time had passed
+    fn advance(bucket : &mut TokenBucket, elapsed : Duration)
+    {
+        bucket.last_refill = bucket.last_refill.checked_sub(elapsed).expect("Instant can go back");
+    }
+
+    #[test]
+    fn allows_burst_up_to_capacity()
+    {
+        let mut bucket = TokenBucket::new(3.0, 1.0);
+        for _ in 0..3
+        {
+            assert!(bucket.try_take().is_ok());
+        }
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn reports_time_until_next_token()
+    {
+        let mut bucket = TokenBucket::new(1.0, 0.5);
+        assert!(bucket.try_take().is_ok());
+        let retry_after = bucket.try_take().unwrap_err();
+        assert!(retry_after > Duration::from_millis(1900) && retry_after <= Duration::from_secs(2), "{:?}", retry_after);
+    }
+
+    #[test]
+    fn refills_over_time()
+    {
+        let mut bucket = TokenBucket::new(2.0, 1.0);
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+
+        advance(&mut bucket, Duration::from_millis(1100));
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn refill_is_capped_at_capacity()
+    {
+        let mut bucket = TokenBucket::new(2.0, 1.0);
+        assert!(bucket.try_take().is_ok());
+        assert!(!bucket.is_full());
+
+        advance(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+}